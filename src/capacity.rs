@@ -0,0 +1,108 @@
+use serde::Serialize;
+use crate::session_common::SessionKind;
+use crate::utils::get_env_or;
+
+/// Limits on the number of sessions (and their contents) this instance will hold.
+/// Read once at startup from the environment.
+#[derive(Debug, Clone)]
+pub struct CapacityLimits {
+    pub max_sessions: usize,
+    pub max_open_sessions: usize,
+    pub max_encrypted_sessions: usize,
+    pub max_values_per_session: usize,
+    pub max_checkpoints_per_session: usize,
+    pub memory_budget_bytes: usize,
+    pub retry_after_secs: u64,
+}
+
+impl CapacityLimits {
+    pub fn from_env() -> Self {
+        Self {
+            max_sessions: get_env_or("MAX_SESSIONS", 512),
+            max_open_sessions: get_env_or("MAX_OPEN_SESSIONS", 512),
+            // each encrypted session holds a 1024 dimension secret key and one LWE per value
+            max_encrypted_sessions: get_env_or("MAX_ENCRYPTED_SESSIONS", 16),
+            max_values_per_session: get_env_or("MAX_VALUES_PER_SESSION", 1000),
+            max_checkpoints_per_session: get_env_or("MAX_CHECKPOINTS_PER_SESSION", 5),
+            // leave headroom below the 200Mi pod limit for the runtime itself
+            memory_budget_bytes: get_env_or("MEMORY_BUDGET_BYTES", 150 * 1024 * 1024),
+            retry_after_secs: get_env_or("RETRY_AFTER_SECS", 5),
+        }
+    }
+
+    pub fn max_sessions_of_kind(&self, kind: SessionKind) -> usize {
+        match kind {
+            SessionKind::Open => self.max_open_sessions,
+            SessionKind::Encrypted => self.max_encrypted_sessions,
+        }
+    }
+
+    /// decide whether one more session of `kind` can be created, given the current counts
+    pub fn admit(&self, total: usize, of_kind: usize, kind: SessionKind, memory_used_bytes: usize) -> Admission {
+        if total >= self.max_sessions {
+            return Admission::InstanceFull;
+        }
+        if memory_used_bytes >= self.memory_budget_bytes {
+            return Admission::MemoryExhausted;
+        }
+        if of_kind >= self.max_sessions_of_kind(kind) {
+            return Admission::KindFull;
+        }
+        Admission::Admitted
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Admitted,
+    // no room for any session on this instance, the load balancer should go elsewhere
+    InstanceFull,
+    // sessions already hold the whole memory budget
+    MemoryExhausted,
+    // no room for this kind of session, the client should back off
+    KindFull,
+}
+
+#[derive(Serialize)]
+pub struct KindCapacity {
+    pub max_sessions: usize,
+    pub sessions: usize,
+    pub remaining: usize,
+}
+
+#[derive(Serialize)]
+pub struct CapacityReport {
+    pub max_sessions: usize,
+    pub sessions: usize,
+    pub remaining: usize,
+    pub open: KindCapacity,
+    pub encrypted: KindCapacity,
+    pub max_values_per_session: usize,
+    pub memory_budget_bytes: usize,
+    pub memory_used_bytes: usize,
+}
+
+impl CapacityReport {
+    pub fn new(limits: &CapacityLimits, open_sessions: usize, encrypted_sessions: usize, memory_used_bytes: usize) -> Self {
+        let sessions = open_sessions + encrypted_sessions;
+        let remaining = limits.max_sessions.saturating_sub(sessions);
+        Self {
+            max_sessions: limits.max_sessions,
+            sessions,
+            remaining,
+            open: KindCapacity {
+                max_sessions: limits.max_open_sessions,
+                sessions: open_sessions,
+                remaining: limits.max_open_sessions.saturating_sub(open_sessions).min(remaining),
+            },
+            encrypted: KindCapacity {
+                max_sessions: limits.max_encrypted_sessions,
+                sessions: encrypted_sessions,
+                remaining: limits.max_encrypted_sessions.saturating_sub(encrypted_sessions).min(remaining),
+            },
+            max_values_per_session: limits.max_values_per_session,
+            memory_budget_bytes: limits.memory_budget_bytes,
+            memory_used_bytes,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+// Label used by checkpoint and rollback actions that do not name one
+pub const DEFAULT_CHECKPOINT: &str = "default";
+
+/// Labelled copies of a session's stored values, at most `max_checkpoints` of them
+pub struct Checkpoints<T> {
+    max_checkpoints: usize,
+    // (label, state, approximate bytes held by the state), oldest first
+    entries: VecDeque<(String, T, usize)>,
+}
+
+impl<T: Clone> Checkpoints<T> {
+    pub fn new(max_checkpoints: usize) -> Self {
+        Self { max_checkpoints, entries: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn held_bytes(&self) -> usize {
+        self.entries.iter().map(|(_, _, bytes)| bytes).sum()
+    }
+
+    /// bytes held by the checkpoint saved under the label, 0 when there is none
+    pub fn bytes_of(&self, label: &str) -> usize {
+        self.entries.iter()
+            .find(|(saved_label, _, _)| saved_label == label)
+            .map_or(0, |(_, _, bytes)| *bytes)
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        self.entries.iter().map(|(label, _, _)| label.clone()).collect()
+    }
+
+    /// Save under a label, replacing an earlier checkpoint with the same label
+    pub fn save(&mut self, label: &str, state: T, bytes: usize) -> Result<(), String> {
+        let position = self.entries.iter().position(|(saved_label, _, _)| saved_label == label);
+        match position {
+            Some(position) => {
+                self.entries.remove(position);
+            }
+            None if self.entries.len() >= self.max_checkpoints => {
+                return Err(format!(
+                    "Session already holds {} checkpoints. Reuse one of the labels {:?}", self.entries.len(), self.labels()
+                ));
+            }
+            None => {}
+        }
+        self.entries.push_back((label.to_string(), state, bytes));
+        Ok(())
+    }
+
+    /// a copy of the state saved under the label, which stays available for later rollbacks
+    pub fn restore(&self, label: &str) -> Option<T> {
+        self.entries.iter()
+            .find(|(saved_label, _, _)| saved_label == label)
+            .map(|(_, state, _)| state.clone())
+    }
+
+    /// a copy with every saved state transformed, or the first error
+    pub fn try_map(&self, f: impl Fn(&T) -> Result<T, String>) -> Result<Self, String> {
+        let entries = self.entries.iter()
+            .map(|(label, state, bytes)| Ok((label.clone(), f(state)?, *bytes)))
+            .collect::<Result<VecDeque<(String, T, usize)>, String>>()?;
+        Ok(Self { max_checkpoints: self.max_checkpoints, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_beyond_the_limit_fails() {
+        let mut checkpoints = Checkpoints::new(2);
+        checkpoints.save("a", vec![1.], 8).unwrap();
+        checkpoints.save("b", vec![2.], 8).unwrap();
+        assert!(checkpoints.save("c", vec![3.], 8).is_err());
+        assert_eq!(checkpoints.labels(), vec!["a", "b"]);
+        assert_eq!(checkpoints.held_bytes(), 16);
+        assert!(Checkpoints::new(0).save(DEFAULT_CHECKPOINT, vec![1.], 8).is_err());
+    }
+
+    #[test]
+    fn saving_a_label_again_replaces_it_even_when_full() {
+        let mut checkpoints = Checkpoints::new(2);
+        checkpoints.save("a", vec![1.], 8).unwrap();
+        checkpoints.save("b", vec![2.], 8).unwrap();
+        checkpoints.save("a", vec![3., 4.], 16).unwrap();
+        assert_eq!(checkpoints.len(), 2);
+        // the replaced checkpoint becomes the newest
+        assert_eq!(checkpoints.labels(), vec!["b", "a"]);
+        assert_eq!(checkpoints.bytes_of("a"), 16);
+        assert_eq!(checkpoints.bytes_of("c"), 0);
+        assert_eq!(checkpoints.held_bytes(), 24);
+        assert_eq!(checkpoints.restore("a"), Some(vec![3., 4.]));
+    }
+
+    #[test]
+    fn restoring_keeps_the_checkpoint() {
+        let mut checkpoints = Checkpoints::new(1);
+        checkpoints.save(DEFAULT_CHECKPOINT, vec![1.], 8).unwrap();
+        assert_eq!(checkpoints.restore(DEFAULT_CHECKPOINT), Some(vec![1.]));
+        assert_eq!(checkpoints.restore(DEFAULT_CHECKPOINT), Some(vec![1.]));
+        assert_eq!(checkpoints.restore("missing"), None);
+    }
+
+    #[test]
+    fn try_map_keeps_labels_bytes_and_limit() {
+        let mut checkpoints = Checkpoints::new(2);
+        checkpoints.save("a", vec![1.], 8).unwrap();
+        checkpoints.save("b", vec![2.], 8).unwrap();
+        let mut doubled = checkpoints.try_map(|state| Ok(state.iter().map(|v| v * 2.).collect())).unwrap();
+        assert_eq!(doubled.labels(), vec!["a", "b"]);
+        assert_eq!(doubled.restore("b"), Some(vec![4.]));
+        assert_eq!(doubled.held_bytes(), 16);
+        assert!(doubled.save("c", vec![], 0).is_err());
+        assert_eq!(checkpoints.try_map(|_| Err(String::from("failed"))).err(), Some(String::from("failed")));
+    }
+}
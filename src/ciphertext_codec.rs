@@ -0,0 +1,48 @@
+use concrete::*;
+
+// Ciphertexts travel as bincode, base64 encoded when embedded in json messages.
+// Any concrete based tool can read them back with bincode and concrete's serde derives.
+
+pub fn encode(ciphertext: &LWE) -> Result<String, String> {
+    bincode::serialize(ciphertext)
+        .map(base64::encode)
+        .map_err(|e| format!("Failed to serialize ciphertext. {}", e))
+}
+
+pub fn decode(encoded: &str) -> Result<LWE, String> {
+    let bytes = base64::decode(encoded).map_err(|e| format!("Invalid base64 ciphertext. {}", e))?;
+    bincode::deserialize(&bytes).map_err(|e| format!("Failed to deserialize ciphertext. {}", e))
+}
+
+/// several ciphertexts as one base64 encoded bincode `Vec<LWE>`, the binary download format
+pub fn encode_all(ciphertexts: &[LWE]) -> Result<String, String> {
+    bincode::serialize(ciphertexts)
+        .map(base64::encode)
+        .map_err(|e| format!("Failed to serialize ciphertexts. {}", e))
+}
+
+pub fn decode_all(encoded: &str) -> Result<Vec<LWE>, String> {
+    let bytes = base64::decode(encoded).map_err(|e| format!("Invalid base64 ciphertexts. {}", e))?;
+    bincode::deserialize(&bytes).map_err(|e| format!("Failed to deserialize ciphertexts. {}", e))
+}
+
+/// An uploaded ciphertext must come from a key and encoder with the session's parameters
+pub fn check_compatible(ciphertext: &LWE, dimensions: usize, encoder: &Encoder) -> Result<(), String> {
+    if ciphertext.dimension != dimensions {
+        return Err(format!("Ciphertext has {} dimensions, session key has {}", ciphertext.dimension, dimensions));
+    }
+    let ciphertext_encoder = &ciphertext.encoder;
+    if ciphertext_encoder.o != encoder.o
+        || ciphertext_encoder.delta != encoder.delta
+        || ciphertext_encoder.nb_bit_precision != encoder.nb_bit_precision
+        || ciphertext_encoder.nb_bit_padding != encoder.nb_bit_padding
+    {
+        return Err(format!(
+            "Ciphertext encoder [{}, {}] with {} bits of precision and {} of padding does not match the session's [{}, {}] with {} and {}",
+            ciphertext_encoder.o, ciphertext_encoder.o + ciphertext_encoder.delta,
+            ciphertext_encoder.nb_bit_precision, ciphertext_encoder.nb_bit_padding,
+            encoder.o, encoder.o + encoder.delta, encoder.nb_bit_precision, encoder.nb_bit_padding,
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+
+/// Counters describing the work going through a `CryptoPool`
+#[derive(Debug, Default)]
+pub struct CryptoPoolStats {
+    pub queued: AtomicUsize,
+    pub running: AtomicUsize,
+    pub completed: AtomicU64,
+    pub cancelled: AtomicU64,
+    pub queue_wait_micros: AtomicU64,
+}
+
+// Increments a gauge on creation and decrements it on drop
+struct GaugeGuard<'a>(&'a AtomicUsize);
+
+impl<'a> GaugeGuard<'a> {
+    fn new(gauge: &'a AtomicUsize) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl<'a> Drop for GaugeGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Bounded pool for CPU heavy crypto work (key generation, encryption, homomorphic ops).
+/// Jobs run on tokio's blocking threads, at most `workers` at a time, so the async
+/// workers serving HTTP requests are never starved.
+#[derive(Debug)]
+pub struct CryptoPool {
+    workers: usize,
+    permits: Arc<Semaphore>,
+    stats: Arc<CryptoPoolStats>,
+}
+
+impl CryptoPool {
+    pub fn new(workers: usize) -> Arc<Self> {
+        let workers = workers.max(1);
+        Arc::new(Self {
+            workers,
+            permits: Arc::new(Semaphore::new(workers)),
+            stats: Arc::new(CryptoPoolStats::default()),
+        })
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn stats(&self) -> &CryptoPoolStats {
+        &self.stats
+    }
+
+    /// Run `job` on the pool and wait for its result.
+    /// Dropping the returned future while the job is still queued cancels it; a job that
+    /// has already started runs to completion and keeps its worker until then.
+    pub async fn run<F, T>(&self, job: F) -> Result<T, String>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued_at = Instant::now();
+        let queued = GaugeGuard::new(&self.stats.queued);
+        let mut cancel_guard = CancelGuard { stats: &self.stats, armed: true };
+
+        let permit = match self.permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(e) => return Err(format!("Crypto pool closed. {}", e)),
+        };
+        drop(queued);
+        cancel_guard.armed = false;
+        self.stats.queue_wait_micros.fetch_add(queued_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        let stats = self.stats.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // the permit moves with the job, so the worker stays taken until the job is done
+            let _permit = permit;
+            let _running = GaugeGuard::new(&stats.running);
+            let output = job();
+            stats.completed.fetch_add(1, Ordering::Relaxed);
+            output
+        }).await;
+        match result {
+            Ok(output) => Ok(output),
+            Err(e) => Err(format!("Crypto job failed. {}", e)),
+        }
+    }
+}
+
+// Counts a job as cancelled if it is dropped before getting a worker
+struct CancelGuard<'a> {
+    stats: &'a CryptoPoolStats,
+    armed: bool,
+}
+
+impl<'a> Drop for CancelGuard<'a> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.stats.cancelled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
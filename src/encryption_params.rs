@@ -0,0 +1,295 @@
+use concrete::LWESecretKey;
+use serde::{Deserialize, Serialize};
+
+use crate::homomorphic;
+
+// Smallest log2 standard deviation of the key noise for 80 and 128 bits of security at a given
+// key dimension, from concrete's LWE80_* and LWE128_* parameter sets.
+// https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe_params.rs
+const SECURITY_TABLE: [(usize, i32, i32); 9] = [
+    // (dimension, 80 bits, 128 bits)
+    (256, -9, -5),
+    (512, -19, -11),
+    (630, -24, -14),
+    (650, -25, -15),
+    (688, -26, -16),
+    (750, -29, -17),
+    (800, -31, -19),
+    (1024, -40, -25),
+    (2048, -82, -52),
+];
+
+/// Named parameter sets, explicit parameters in the init message override them
+pub const PRESETS: [&str; 3] = ["secure80", "secure128", "fast80"];
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EncryptionParameters {
+    pub encoder_min: f64,
+    pub encoder_max: f64,
+    pub encoder_precision_bits: usize,
+    pub encoder_padding_bits: usize,
+    pub secret_key_dimensions: usize,
+    pub secret_key_log2_std_dev: i32,
+    // generate bootstrapping keys, to compute means once the padding is used up and variances
+    pub bootstrap: bool,
+}
+impl Default for EncryptionParameters {
+    fn default() -> Self {
+        Self {
+            encoder_min: 0.,
+            encoder_max: 100.,
+            encoder_precision_bits: 16,
+            // the carries of sums of 1000 values, and a bit for the multiplication of means
+            encoder_padding_bits: 11,
+            secret_key_dimensions: 1024,
+            secret_key_log2_std_dev: -40,
+            bootstrap: false,
+        }
+    }
+}
+
+// Encryption parameters as sent in the init message, every field is optional
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct EncryptionParametersRequest {
+    preset: Option<String>,
+    encoder_min: Option<f64>,
+    encoder_max: Option<f64>,
+    encoder_precision_bits: Option<usize>,
+    encoder_padding_bits: Option<usize>,
+    secret_key_dimensions: Option<usize>,
+    secret_key_log2_std_dev: Option<i32>,
+    bootstrap: Option<bool>,
+}
+
+fn preset(name: &str) -> Result<EncryptionParameters, String> {
+    let defaults = EncryptionParameters::default();
+    match name {
+        // the defaults
+        "secure80" => Ok(defaults),
+        // the larger noise leaves room for fewer bits of precision
+        "secure128" => Ok(EncryptionParameters {
+            encoder_precision_bits: 6,
+            secret_key_dimensions: 1024,
+            secret_key_log2_std_dev: -25,
+            ..defaults
+        }),
+        // a smaller key for faster key generation and smaller ciphertexts
+        "fast80" => Ok(EncryptionParameters {
+            encoder_precision_bits: 5,
+            secret_key_dimensions: 630,
+            secret_key_log2_std_dev: -24,
+            ..defaults
+        }),
+        _ => Err(format!("Unknown preset {}. Known presets: {}", name, PRESETS.join(", "))),
+    }
+}
+
+/// Parse the init message, starting from the named preset or the defaults
+pub fn parse(init_message: &str) -> Result<EncryptionParameters, String> {
+    let request: EncryptionParametersRequest = serde_json::from_str(init_message)
+        .map_err(|e| format!("Failed to json decode encryption parameters. {}", e))?;
+    let base = match request.preset.as_deref() {
+        Some(name) => preset(name)?,
+        None => EncryptionParameters::default(),
+    };
+    Ok(EncryptionParameters {
+        encoder_min: request.encoder_min.unwrap_or(base.encoder_min),
+        encoder_max: request.encoder_max.unwrap_or(base.encoder_max),
+        encoder_precision_bits: request.encoder_precision_bits.unwrap_or(base.encoder_precision_bits),
+        encoder_padding_bits: request.encoder_padding_bits.unwrap_or(base.encoder_padding_bits),
+        secret_key_dimensions: request.secret_key_dimensions.unwrap_or(base.secret_key_dimensions),
+        secret_key_log2_std_dev: request.secret_key_log2_std_dev.unwrap_or(base.secret_key_log2_std_dev),
+        bootstrap: request.bootstrap.unwrap_or(base.bootstrap),
+    })
+}
+
+/// Estimated bits of security of a key, 0 when below 80 bits
+pub fn security_bits(dimensions: usize, log2_std_dev: i32) -> u32 {
+    // the closest tabulated dimension not above the key's is a conservative estimate
+    match SECURITY_TABLE.iter().rev().find(|(dimension, _, _)| *dimension <= dimensions) {
+        Some((_, _, std_dev_128)) if log2_std_dev >= *std_dev_128 => 128,
+        Some((_, std_dev_80, _)) if log2_std_dev >= *std_dev_80 => 80,
+        _ => 0,
+    }
+}
+
+// smallest log2 std dev giving the requested security at this dimension
+fn required_log2_std_dev(dimensions: usize, min_security_bits: u32) -> Option<i32> {
+    let (_, std_dev_80, std_dev_128) = SECURITY_TABLE.iter().rev().find(|(dimension, _, _)| *dimension <= dimensions)?;
+    Some(if min_security_bits > 80 { *std_dev_128 } else { *std_dev_80 })
+}
+
+impl EncryptionParameters {
+    /// log2 of the noise standard deviation after summing `count` fresh ciphertexts, with 3 sigma margin
+    fn log2_sum_noise(&self, count: usize) -> f64 {
+        self.secret_key_log2_std_dev as f64 + 0.5 * (count.max(1) as f64).log2() + 3f64.log2()
+    }
+
+    /// Most bits of precision a sum of `count` values keeps under the key's noise
+    pub fn max_precision_bits(&self, count: usize) -> i64 {
+        (-self.log2_sum_noise(count) - self.encoder_padding_bits as f64 - 1.).floor() as i64
+    }
+
+    /// Bits of precision of the mean of up to `count` values, 1/n being rounded to the padding
+    /// bits the sum's carries leave
+    pub fn mean_precision_bits(&self, count: usize) -> usize {
+        self.encoder_precision_bits.min(self.encoder_padding_bits.saturating_sub(homomorphic::carry_bits(count)))
+    }
+
+    /// Check that the parameters are secure enough and can carry `max_values` additions
+    /// and one multiplication per value, explaining which parameter to change otherwise.
+    pub fn validate(&self, max_values: usize, min_security_bits: u32) -> Result<(), String> {
+        if self.encoder_min >= self.encoder_max || self.encoder_min.is_nan() || self.encoder_max.is_nan() {
+            return Err(format!(
+                "encoder_max ({}) must be greater than encoder_min ({})", self.encoder_max, self.encoder_min
+            ));
+        }
+        if self.encoder_precision_bits == 0 {
+            return Err(String::from("encoder_precision_bits must be at least 1"));
+        }
+        if self.encoder_padding_bits == 0 {
+            return Err(String::from(
+                "encoder_padding_bits must be at least 1, mean and weighted actions multiply by a float constant and bootstraps need a bit of padding"
+            ));
+        }
+        // sums spend a bit of padding per doubling of the number of values, means one more
+        let required_padding = homomorphic::carry_bits(max_values) + 1;
+        if self.encoder_padding_bits < required_padding {
+            return Err(format!(
+                "encoder_padding_bits ({}) can not hold the carries of a sum of max_values ({}) values and the multiplication of their mean. Raise encoder_padding_bits to at least {}",
+                self.encoder_padding_bits, max_values, required_padding
+            ));
+        }
+        let security = security_bits(self.secret_key_dimensions, self.secret_key_log2_std_dev);
+        if security < min_security_bits {
+            return Err(match required_log2_std_dev(self.secret_key_dimensions, min_security_bits) {
+                Some(required) => format!(
+                    "Parameters give less than {} bits of security. Raise secret_key_log2_std_dev from {} to at least {}, or raise secret_key_dimensions",
+                    min_security_bits, self.secret_key_log2_std_dev, required
+                ),
+                None => format!(
+                    "Parameters give less than {} bits of security. Raise secret_key_dimensions from {} to at least {}",
+                    min_security_bits, self.secret_key_dimensions, SECURITY_TABLE[0].0
+                ),
+            });
+        }
+        let max_precision = self.max_precision_bits(max_values);
+        if (self.encoder_precision_bits as i64) > max_precision {
+            return Err(format!(
+                "Noise of a sum of {} values overwhelms {} bits of precision with {} bits of padding. Lower encoder_precision_bits to at most {}, or lower encoder_padding_bits, or use a key with less noise",
+                max_values, self.encoder_precision_bits, self.encoder_padding_bits, max_precision.max(0)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct KeyImportRequest {
+    secret_key: Option<String>,
+}
+
+/// Whether the init message carries a secret key to import
+pub fn imports_key(init_message: &str) -> bool {
+    serde_json::from_str::<KeyImportRequest>(init_message).ok().and_then(|request| request.secret_key).is_some()
+}
+
+/// Client provided secret key from the init message, serialized as by the export_key action.
+/// It must match the key parameters, so validation applies to it.
+pub fn imported_key(init_message: &str, parameters: &EncryptionParameters) -> Result<Option<LWESecretKey>, String> {
+    let request: KeyImportRequest = serde_json::from_str(init_message)
+        .map_err(|e| format!("Failed to json decode encryption parameters. {}", e))?;
+    let serialized = match request.secret_key {
+        Some(serialized) => serialized,
+        None => return Ok(None),
+    };
+    let secret_key: LWESecretKey = serde_json::from_str(&serialized)
+        .map_err(|e| format!("Failed to decode secret_key. {}", e))?;
+    if secret_key.dimension != parameters.secret_key_dimensions {
+        return Err(format!(
+            "secret_key has {} dimensions, set secret_key_dimensions to {}", secret_key.dimension, secret_key.dimension
+        ));
+    }
+    let log2_std_dev = secret_key.std_dev.log2().round() as i32;
+    if log2_std_dev != parameters.secret_key_log2_std_dev {
+        return Err(format!(
+            "secret_key has a log2 standard deviation of {}, set secret_key_log2_std_dev to {}", log2_std_dev, log2_std_dev
+        ));
+    }
+    Ok(Some(secret_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn security_bits_follow_the_closest_smaller_dimension() {
+        assert_eq!(security_bits(1024, -40), 80);
+        assert_eq!(security_bits(1024, -25), 128);
+        assert_eq!(security_bits(1024, -41), 0);
+        // 1000 dimensions are rated as 800
+        assert_eq!(security_bits(1000, -31), 80);
+        assert_eq!(security_bits(1000, -32), 0);
+        assert_eq!(security_bits(4096, -52), 128);
+        assert_eq!(security_bits(128, 0), 0);
+    }
+
+    #[test]
+    fn presets_are_valid_at_their_security() {
+        for (name, min_security_bits) in [("secure80", 80), ("secure128", 128), ("fast80", 80)].iter() {
+            let parameters = parse(&format!(r#"{{"preset": "{}"}}"#, name)).unwrap();
+            assert_eq!(parameters.validate(1000, *min_security_bits), Ok(()), "{}", name);
+        }
+        assert!(parse(r#"{"preset": "fast80"}"#).unwrap().validate(1000, 128).is_err());
+        assert!(parse(r#"{"preset": "secure256"}"#).is_err());
+    }
+
+    #[test]
+    fn explicit_parameters_override_the_preset() {
+        let parameters = parse(r#"{"preset": "secure128", "encoder_max": 10, "bootstrap": true}"#).unwrap();
+        assert_eq!(parameters.encoder_max, 10.);
+        assert!(parameters.bootstrap);
+        assert_eq!(parameters.secret_key_log2_std_dev, -25);
+        assert_eq!(parse("{}").unwrap().secret_key_log2_std_dev, EncryptionParameters::default().secret_key_log2_std_dev);
+    }
+
+    #[test]
+    fn validation_rejects_unusable_parameters() {
+        let defaults = EncryptionParameters::default();
+        let invalid = |parameters: EncryptionParameters| parameters.validate(1000, 80).is_err();
+        assert!(invalid(EncryptionParameters { encoder_min: 100., ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { encoder_max: f64::NAN, ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { encoder_precision_bits: 0, ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { encoder_padding_bits: 0, ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { encoder_padding_bits: 0, bootstrap: true, ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { secret_key_log2_std_dev: -60, ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { secret_key_dimensions: 128, ..defaults.clone() }));
+        assert!(invalid(EncryptionParameters { encoder_precision_bits: 40, ..defaults }));
+    }
+
+    #[test]
+    fn padding_holds_the_carries_of_max_values() {
+        let parameters = EncryptionParameters { encoder_padding_bits: 10, ..EncryptionParameters::default() };
+        assert!(parameters.validate(512, 80).is_ok());
+        let error = parameters.validate(1000, 80).unwrap_err();
+        assert!(error.contains("encoder_padding_bits") && error.contains("max_values"), "{}", error);
+        assert!(error.contains("at least 11"), "{}", error);
+        assert_eq!(parameters.mean_precision_bits(4), 8);
+        assert_eq!(parameters.mean_precision_bits(1000), 0);
+    }
+
+    #[test]
+    fn precision_shrinks_as_sums_grow() {
+        let parameters = EncryptionParameters::default();
+        // 40 bits of noise margin, less 3 sigma, the padding and a bit of rounding
+        assert_eq!(parameters.max_precision_bits(1), 26);
+        assert_eq!(parameters.max_precision_bits(1 << 10), 21);
+        assert!(parameters.validate(1 << 10, 80).is_ok());
+        let precise = EncryptionParameters { encoder_precision_bits: 24, ..parameters };
+        assert!(precise.validate(1, 80).is_ok());
+        assert!(precise.validate(1 << 10, 80).is_err());
+    }
+}
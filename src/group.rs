@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use hmac::{Hmac, Mac, NewMac};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+use crate::session_common::SessionRequestMessage;
+use crate::statistics;
+
+lazy_static! {
+    // participant tokens are derived from the session id with this key, so a restarted or
+    // recreated session accepts the tokens handed out when it was first created
+    static ref TOKEN_KEY: Vec<u8> = [*Uuid::new_v4().as_bytes(), *Uuid::new_v4().as_bytes()].concat();
+}
+
+// actions releasing an aggregate of a stream, once a quorum of participants contributed to it,
+// along with every statistics action
+const RELEASE_ACTIONS: [&str; 7] = [
+    "mean", "weighted_mean", "dot", "vector_mean", "vector_sum", "rolling", "download_sum",
+];
+// the only actions of a group hiding contributions, none of them exposes a single value
+const HIDDEN_CONTRIBUTIONS_ACTIONS: [&str; 9] = [
+    "encrypt", "encrypt_vector", "mean", "sum", "vector_mean", "vector_sum", "contributions", "list_streams", "shutdown",
+];
+
+/// Group section of an init message, for sessions shared by several participants:
+/// {"group": {"participants": ["alice", "bob", "carol"], "quorum": 2, "hide_contributions": true}}
+#[derive(Deserialize, Debug, Clone)]
+pub struct GroupConfig {
+    pub participants: Vec<String>,
+    // distinct participants who must contribute to a stream before an aggregate of it is released, all of them by default
+    #[serde(default)]
+    pub quorum: Option<usize>,
+    // restrict the group to actions that never expose a single participant's values
+    #[serde(default)]
+    pub hide_contributions: bool,
+}
+
+impl GroupConfig {
+    /// The group of an init message, None for single owner sessions
+    pub fn parse(init_message: &str) -> Result<Option<Self>, String> {
+        // init messages that are not json objects have always been accepted by open sessions
+        let group = match serde_json::from_str::<serde_json::Value>(init_message) {
+            Ok(serde_json::Value::Object(mut fields)) => fields.remove("group"),
+            _ => None,
+        };
+        let group = match group {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+        let config: GroupConfig = serde_json::from_value(group).map_err(|e| format!("Invalid group. {}", e))?;
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.participants.is_empty() {
+            return Err(String::from("Invalid group. A group needs at least one participant"));
+        }
+        let mut names = HashSet::new();
+        for participant in &self.participants {
+            if participant.is_empty() || !names.insert(participant) {
+                return Err(format!("Invalid group. Participant names must be unique and not empty, got {:?}", participant));
+            }
+        }
+        let quorum = self.quorum();
+        if quorum == 0 || quorum > self.participants.len() {
+            return Err(format!("Invalid group. Quorum must be between 1 and {}, got {}", self.participants.len(), quorum));
+        }
+        // an aggregate of a single participant is that participant's values
+        if self.hide_contributions && quorum < 2 {
+            return Err(format!("Invalid group. Groups hiding contributions need a quorum of at least 2, got {}", quorum));
+        }
+        Ok(())
+    }
+
+    fn quorum(&self) -> usize {
+        self.quorum.unwrap_or(self.participants.len())
+    }
+}
+
+fn is_release_action(action: &str) -> bool {
+    RELEASE_ACTIONS.contains(&action) || statistics::is_statistics_action(action)
+}
+
+// values and vectors of a stream are aggregated apart, so each needs its own quorum
+fn collection(request_message: &SessionRequestMessage) -> String {
+    let action = request_message.action.as_str();
+    if action == "encrypt_vector" || action == "vector_mean" || action == "vector_sum" {
+        format!("{} vectors", request_message.stream_name())
+    } else {
+        request_message.stream_name().to_string()
+    }
+}
+
+/// Token of a participant, sent with each of their actions as participant_token
+fn participant_token(sessionid: &str, participant: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&TOKEN_KEY).expect("HMAC takes keys of any length");
+    mac.update(sessionid.as_bytes());
+    mac.update(b"\n");
+    mac.update(participant.as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ParticipantToken {
+    pub participant: String,
+    pub token: String,
+}
+
+/// Tokens of the participants of a group session, None for single owner sessions
+pub fn participant_tokens(sessionid: &str, init_message: &str) -> Option<Vec<ParticipantToken>> {
+    let config = GroupConfig::parse(init_message).ok()??;
+    Some(config.participants.iter()
+        .map(|participant| ParticipantToken { participant: participant.clone(), token: participant_token(sessionid, participant) })
+        .collect())
+}
+
+/// Whether the holder of `token` may observe a session's events: anyone for single owner
+/// sessions, the participants for group sessions
+pub fn admits_observer(sessionid: &str, init_message: &str, token: Option<&str>) -> bool {
+    match GroupConfig::parse(init_message) {
+        Ok(Some(config)) => match token {
+            Some(token) => config.participants.iter().any(|participant| participant_token(sessionid, participant) == token),
+            None => false,
+        },
+        _ => true,
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Contributions {
+    pub participant: String,
+    pub contributions: usize,
+}
+
+/// Participants of a group session and what they contributed. Contribution counts start
+/// over when a crashed session restarts, so a quorum is only ever met again, never kept.
+#[derive(Debug, Clone)]
+pub struct Group {
+    config: GroupConfig,
+    // token -> participant
+    tokens: HashMap<String, String>,
+    // participant -> values contributed
+    contributions: HashMap<String, usize>,
+    // participants who contributed to the values or vectors each stream holds now
+    contributors: HashMap<String, HashSet<String>>,
+}
+
+impl Group {
+    pub fn new(sessionid: &str, config: GroupConfig) -> Self {
+        let tokens = config.participants.iter()
+            .map(|participant| (participant_token(sessionid, participant), participant.clone()))
+            .collect();
+        Self { config, tokens, contributions: HashMap::new(), contributors: HashMap::new() }
+    }
+
+    pub fn hides_contributions(&self) -> bool {
+        self.config.hide_contributions
+    }
+
+    /// The participant sending an action, when the action is open to them now. In a group
+    /// hiding contributions, each release spends the quorum: aggregates that leave the values
+    /// in place could otherwise be compared before and after a single contribution.
+    pub fn admit(&mut self, request_message: &SessionRequestMessage) -> Result<String, String> {
+        let token = request_message.participant_token.as_deref()
+            .ok_or_else(|| String::from("Group sessions need the participant_token of a participant"))?;
+        let participant = self.tokens.get(token).cloned().ok_or_else(|| String::from("Unknown participant token"))?;
+        let action = request_message.action.as_str();
+        if self.config.hide_contributions && !HIDDEN_CONTRIBUTIONS_ACTIONS.contains(&action) {
+            return Err(format!("Action {} is not available, the group hides individual contributions", action));
+        }
+        if is_release_action(action) {
+            let collection = collection(request_message);
+            let contributors = self.contributors.get(&collection).map_or(0, HashSet::len);
+            if contributors < self.config.quorum() {
+                return Err(format!(
+                    "Quorum not reached. {} of the {} participants needed contributed to stream {}",
+                    contributors, self.config.quorum(), request_message.stream_name()
+                ));
+            }
+            if self.config.hide_contributions {
+                self.contributors.remove(&collection);
+            }
+        }
+        Ok(participant)
+    }
+
+    /// Record values a participant added with an action, to values or vectors of a stream
+    /// that held `held_before` of them. Once emptied, they need a new quorum.
+    pub fn contributed(&mut self, participant: &str, request_message: &SessionRequestMessage, count: usize, held_before: usize) {
+        let contributors = self.contributors.entry(collection(request_message)).or_default();
+        if held_before == 0 {
+            contributors.clear();
+        }
+        contributors.insert(participant.to_string());
+        *self.contributions.entry(participant.to_string()).or_insert(0) += count;
+    }
+
+    /// values contributed by each participant, in the order of the group
+    pub fn contributions(&self) -> Vec<Contributions> {
+        self.config.participants.iter()
+            .map(|participant| Contributions {
+                participant: participant.clone(),
+                contributions: self.contributions.get(participant).copied().unwrap_or(0),
+            })
+            .collect()
+    }
+
+    pub fn quorum(&self) -> usize {
+        self.config.quorum()
+    }
+}
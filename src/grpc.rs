@@ -0,0 +1,170 @@
+use axum::http::StatusCode;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status, Streaming};
+
+use crate::session_common::SessionKind;
+use crate::{execute_create_session, execute_session_action, requested_session_id, with_idempotency_key, SharedState, LOCALIP};
+
+pub mod proto {
+    tonic::include_proto!("stickyapp");
+}
+use proto::session_service_server::SessionService;
+pub use proto::session_service_server::SessionServiceServer;
+use proto::{
+    CreateSessionRequest, DeleteSessionRequest, ListSessionsRequest, ListSessionsResponse, SessionActionRequest,
+    SessionResponse,
+};
+
+// responses buffered per StreamActions call before the stream stops reading requests
+const STREAM_BUFFER: usize = 16;
+
+/// gRPC front end over the same sessions as the http routes
+pub struct GrpcSessions {
+    state: SharedState,
+}
+
+impl GrpcSessions {
+    pub fn new(state: SharedState) -> Self {
+        Self { state }
+    }
+}
+
+fn session_response(response: crate::SessionResponse) -> SessionResponse {
+    SessionResponse {
+        status: response.status,
+        message: response.message,
+        sessionid: response.sessionid,
+        participant_tokens: response.participants.unwrap_or_default()
+            .into_iter()
+            .map(|participant| (participant.participant, participant.token))
+            .collect(),
+    }
+}
+
+fn grpc_code(status_code: StatusCode) -> Code {
+    match status_code {
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        StatusCode::GONE | StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::AlreadyExists,
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        _ => Code::Internal,
+    }
+}
+
+// the sticky routing headers of the http api, as metadata
+fn routing_metadata(metadata: &mut MetadataMap, sessionid: Option<&str>) {
+    if let Some(sessionid) = sessionid {
+        if let Ok(value) = sessionid.parse() {
+            metadata.insert("x-sessionid", value);
+        }
+    }
+    if let Ok(value) = LOCALIP.as_str().parse() {
+        metadata.insert("x-sessionlocation", value);
+    }
+}
+
+// Successful outcomes are responses, possibly with status false, others become errors
+fn grpc_result(
+    status_code: StatusCode,
+    response: crate::SessionResponse,
+    retry_after_secs: u64,
+) -> Result<Response<SessionResponse>, Box<Status>> {
+    if status_code != StatusCode::OK {
+        let mut metadata = MetadataMap::new();
+        routing_metadata(&mut metadata, None);
+        if status_code == StatusCode::TOO_MANY_REQUESTS || status_code == StatusCode::SERVICE_UNAVAILABLE {
+            if let Ok(value) = retry_after_secs.to_string().parse() {
+                metadata.insert("retry-after", value);
+            }
+        }
+        return Err(Box::new(Status::with_metadata(grpc_code(status_code), response.message, metadata)));
+    }
+    let sessionid = response.sessionid.clone();
+    let mut grpc_response = Response::new(session_response(response));
+    routing_metadata(grpc_response.metadata_mut(), Some(sessionid.as_str()));
+    Ok(grpc_response)
+}
+
+#[tonic::async_trait]
+impl SessionService for GrpcSessions {
+    async fn create_session(&self, request: Request<CreateSessionRequest>) -> Result<Response<SessionResponse>, Status> {
+        let create_request = request.into_inner();
+        let session_kind = if create_request.encrypted { SessionKind::Encrypted } else { SessionKind::Open };
+        tracing::debug!("grpc create_session request received. Kind: {:?}", session_kind);
+        // proto3 strings are empty when unset
+        let sessionid = Some(create_request.sessionid).filter(|sessionid| !sessionid.is_empty());
+        let idempotency_key = Some(create_request.idempotency_key.as_str()).filter(|key| !key.is_empty());
+        let sessionid = requested_session_id(session_kind, sessionid, idempotency_key)
+            .map_err(|e| Status::invalid_argument(format!("Failure while creating session. {}", e)))?;
+        let outcome = execute_create_session(&self.state, session_kind, create_request.message, sessionid, idempotency_key, None).await;
+        grpc_result(outcome.status_code, outcome.response, outcome.retry_after_secs).map_err(|status| *status)
+    }
+
+    async fn session_action(&self, request: Request<SessionActionRequest>) -> Result<Response<SessionResponse>, Status> {
+        let idempotency_key = request.metadata().get("idempotency-key").and_then(|key| key.to_str().ok()).map(String::from);
+        let action_request = request.into_inner();
+        tracing::debug!("[{}] grpc session_action request received", action_request.sessionid);
+        let message = match idempotency_key {
+            Some(idempotency_key) => with_idempotency_key(action_request.message, &idempotency_key),
+            None => action_request.message,
+        };
+        let outcome = execute_session_action(&self.state, &action_request.sessionid, message).await;
+        grpc_result(outcome.status_code, outcome.response, outcome.retry_after_secs).map_err(|status| *status)
+    }
+
+    async fn list_sessions(&self, _request: Request<ListSessionsRequest>) -> Result<Response<ListSessionsResponse>, Status> {
+        tracing::info!("grpc list_sessions request received");
+        let sessionids = self.state.read().unwrap().db.keys().cloned().collect();
+        Ok(Response::new(ListSessionsResponse { message: String::from("Ok"), sessionids }))
+    }
+
+    async fn delete_session(&self, request: Request<DeleteSessionRequest>) -> Result<Response<SessionResponse>, Status> {
+        let sessionid = request.into_inner().sessionid;
+        tracing::debug!("[{}] grpc delete_session request received", sessionid);
+        let message = serde_json::json!({"action": "shutdown"}).to_string();
+        let outcome = execute_session_action(&self.state, &sessionid, message).await;
+        grpc_result(outcome.status_code, outcome.response, outcome.retry_after_secs).map_err(|status| *status)
+    }
+
+    type StreamActionsStream = ReceiverStream<Result<SessionResponse, Status>>;
+
+    async fn stream_actions(
+        &self,
+        request: Request<Streaming<SessionActionRequest>>,
+    ) -> Result<Response<Self::StreamActionsStream>, Status> {
+        let idempotency_key = request.metadata().get("idempotency-key").and_then(|key| key.to_str().ok()).map(String::from);
+        let mut inbound = request.into_inner();
+        let (response_tx, response_rx) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            let mut position: u64 = 0;
+            loop {
+                let action_request = match inbound.message().await {
+                    Ok(Some(action_request)) => action_request,
+                    Ok(None) => break,
+                    Err(status) => {
+                        tracing::warn!("grpc stream_actions request stream failed. {}", status);
+                        break;
+                    }
+                };
+                // a stream's key covers all of its actions, each is keyed by its position in the stream
+                // so a retried stream replays them one by one
+                position += 1;
+                let message = match idempotency_key.as_deref() {
+                    Some(idempotency_key) => with_idempotency_key(action_request.message, &format!("{}/{}", idempotency_key, position)),
+                    None => action_request.message,
+                };
+                // failures are answered with status false, so one failed action does not end the stream
+                let outcome = execute_session_action(&state, &action_request.sessionid, message).await;
+                if response_tx.send(Ok(session_response(outcome.response))).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut response = Response::new(ReceiverStream::new(response_rx));
+        routing_metadata(response.metadata_mut(), None);
+        Ok(response)
+    }
+}
@@ -0,0 +1,656 @@
+use concrete::*;
+use serde::Serialize;
+
+// Multiplying by a float constant consumes padding bits, and the result keeps at most as many
+// bits of precision as padding bits were used. All remaining padding goes into the multiplication.
+fn mul_padding_bits(encoder: &Encoder) -> Result<usize, String> {
+    match encoder.nb_bit_padding {
+        0 => Err(String::from("No padding left to multiply with a float value, raise encoder_padding_bits")),
+        nb_bit_padding => Ok(nb_bit_padding),
+    }
+}
+
+/// A decrypted result, with a bound on how far it may be from the exact value
+/// and the encoding of the ciphertext it came from
+#[derive(Debug, Clone, Copy)]
+pub struct Decrypted {
+    pub value: f64,
+    pub error_bound: f64,
+    pub interval: (f64, f64),
+    pub precision_bits: usize,
+    pub padding_bits: usize,
+}
+
+impl Decrypted {
+    fn new(value: f64, encoder: &Encoder, variance: f64) -> Self {
+        Self {
+            value,
+            error_bound: error_bound(encoder, variance),
+            interval: (encoder.o, encoder.o + encoder.delta),
+            precision_bits: encoder.nb_bit_precision,
+            padding_bits: encoder.nb_bit_padding,
+        }
+    }
+
+    /// Structured report against the plaintext result when known, flagged against `tolerance` when given
+    pub fn report(&self, reference: Option<f64>, tolerance: Option<f64>) -> PrecisionReport {
+        let absolute_error = reference.map(|reference| (self.value - reference).abs());
+        // without a reference the bound is the best known error
+        let error = absolute_error.unwrap_or(self.error_bound);
+        PrecisionReport {
+            reference,
+            decrypted: self.value,
+            absolute_error,
+            error_bound: self.error_bound,
+            encoder_min: self.interval.0,
+            encoder_max: self.interval.1,
+            precision_bits: self.precision_bits,
+            padding_bits: self.padding_bits,
+            tolerance,
+            within_tolerance: tolerance.map(|tolerance| error <= tolerance),
+        }
+    }
+}
+
+/// Precision and noise of a decrypted result
+#[derive(Serialize, Debug, Clone)]
+pub struct PrecisionReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<f64>,
+    pub decrypted: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub absolute_error: Option<f64>,
+    pub error_bound: f64,
+    // interval of the result's encoder
+    pub encoder_min: f64,
+    pub encoder_max: f64,
+    pub precision_bits: usize,
+    // padding bits left in the result ciphertext
+    pub padding_bits: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub within_tolerance: Option<bool>,
+}
+
+/// Bound on the error of a decrypted ciphertext, in message space: half the encoder's
+/// granularity (rounding) plus three standard deviations of the ciphertext noise
+fn error_bound(encoder: &Encoder, variance: f64) -> f64 {
+    let granularity = encoder.delta / 2f64.powi(encoder.nb_bit_precision as i32);
+    granularity / 2. + 3. * variance.sqrt() * encoder.delta
+}
+
+pub fn decrypt(ciphertext: &LWE, secret_key: &LWESecretKey) -> Result<Decrypted, String> {
+    match ciphertext.decrypt_decode(secret_key) {
+        Ok(value) => Ok(Decrypted::new(value, &ciphertext.encoder, ciphertext.variance)),
+        Err(e) => Err(format!("Failed to decrypt. {}", e)),
+    }
+}
+
+/// Bits of padding the carries of a sum of `count` values take up
+pub fn carry_bits(count: usize) -> usize {
+    count.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+// add `value` into `accumulator`, both in the same interval, doubling the interval so the sum
+// fits in it. Spends a bit of padding.
+fn add_padded(accumulator: &mut LWE, value: &LWE) -> Result<(), String> {
+    // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs#L770
+    accumulator.add_with_padding_inplace(value)
+        .map_err(|e| format!("Failed to add two encrypted values. {}", e))
+}
+
+// double the interval of a ciphertext with a bit of its padding, as an addition would, without
+// changing the ciphertext
+fn widen(ciphertext: &mut LWE) -> Result<(), String> {
+    widen_encoder(&mut ciphertext.encoder)
+}
+
+fn widen_encoder(encoder: &mut Encoder) -> Result<(), String> {
+    if encoder.nb_bit_padding == 0 {
+        return Err(String::from("No padding left to add encrypted values, raise encoder_padding_bits"));
+    }
+    encoder.delta *= 2.;
+    encoder.nb_bit_padding -= 1;
+    Ok(())
+}
+
+// add `value` into `accumulator` keeping the interval's size, its min becoming the sum of the
+// mins. Carries beyond the interval go into the padding, only a multiplication by 1/n brings
+// them back. Any other min would be corrected modulo the interval and lose a carry.
+fn add_carried(accumulator: &mut LWE, value: &LWE) -> Result<(), String> {
+    // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs#L536
+    let new_min = accumulator.encoder.o + value.encoder.o;
+    accumulator.add_with_new_min_inplace(value, new_min)
+        .map_err(|e| format!("Failed to add two encrypted values. {}", e))
+}
+
+// move the interval of a ciphertext by `offset`, and the value it decrypts to with it
+fn translate(ciphertext: &mut LWE, offset: f64) -> Result<(), String> {
+    ciphertext.add_constant_dynamic_encoder_inplace(offset)
+        .map_err(|e| format!("Failed to add a constant to an encrypted value. {}", e))
+}
+
+// sums of up to this many values are added one after the other
+const SEQUENTIAL_SUM_MAX: usize = 16;
+
+/// How a sum over many ciphertexts is carried out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SumStrategy {
+    // add the values one after the other, then multiply by 1/n
+    Sequential,
+    // add pairs of values, then pairs of pairwise sums. Sums double the interval at each level,
+    // means multiply by 1/n after the last one
+    Tree,
+    // multiply every value by 1/n before adding, so the sum stays in the values' interval
+    PreScaled,
+    // bootstrap partial sums into 1/n of their value before their carries use up the padding
+    Bootstrapped,
+}
+
+impl SumStrategy {
+    /// Pick a strategy from the number of values and their encoding. `divide` is set for
+    /// means, which multiply by 1/n rounded to as many bits as there are padding bits.
+    /// Fails when the sum or the mean would not fit in the padding.
+    pub fn choose(count: usize, encoder: &Encoder, divide: bool, can_bootstrap: bool) -> Result<Self, String> {
+        if !divide {
+            check_sum_padding(count, encoder)?;
+            return Ok(SumStrategy::Tree);
+        }
+        let padding = encoder.nb_bit_padding;
+        let carries = carry_bits(count);
+        mul_padding_bits(encoder)?;
+        // 1/n keeps the precision of the mean when the padding covers both
+        if padding >= carries + encoder.nb_bit_precision {
+            return Ok(SumStrategy::PreScaled);
+        }
+        if can_bootstrap {
+            return Ok(SumStrategy::Bootstrapped);
+        }
+        // with no more padding bits than carries, 1/n rounds to a power of two or to 0
+        if carries >= padding {
+            return Err(format!(
+                "A mean of {} values needs more than {} bits of padding, the values have {}. Raise encoder_padding_bits, or set bootstrap",
+                count, carries, padding
+            ));
+        }
+        Ok(if count <= SEQUENTIAL_SUM_MAX { SumStrategy::Sequential } else { SumStrategy::Tree })
+    }
+}
+
+// a sum doubles its interval at every level of its tree, spending a bit of padding each time
+fn check_sum_padding(count: usize, encoder: &Encoder) -> Result<(), String> {
+    let carries = carry_bits(count);
+    if carries > encoder.nb_bit_padding {
+        return Err(format!(
+            "A sum of {} values needs {} bits of padding, the values have {}. Raise encoder_padding_bits",
+            count, carries, encoder.nb_bit_padding
+        ));
+    }
+    Ok(())
+}
+
+// add the operands pairwise, level after level. An odd operand moves up a level through `carry`.
+fn tree<T>(
+    operands: Vec<T>,
+    add: impl Fn(&mut T, &T) -> Result<(), String>,
+    carry: impl Fn(&mut T) -> Result<(), String>,
+) -> Result<T, String> {
+    let mut level = operands;
+    while level.len() > 1 {
+        let mut next_level = Vec::with_capacity(level.len() / 2 + 1);
+        let mut operands = level.into_iter();
+        while let Some(mut left) = operands.next() {
+            match operands.next() {
+                Some(right) => add(&mut left, &right)?,
+                None => carry(&mut left)?,
+            }
+            next_level.push(left);
+        }
+        level = next_level;
+    }
+    level.pop().ok_or_else(|| String::from("No encrypted values"))
+}
+
+// sum whose interval doubles at every level of the tree, so it never wraps around
+fn padded_sum(values: Vec<LWE>) -> Result<LWE, String> {
+    // the odd operand doubles its interval too, the operands of a level must share theirs
+    tree(values, add_padded, widen)
+}
+
+fn sequential_sum(values: &[LWE]) -> Result<LWE, String> {
+    let (first, rest) = match values.split_first() {
+        Some(split) => split,
+        None => return Err(String::from("No encrypted values")),
+    };
+    let mut encrypted_sum = first.clone();
+    for encrypted_value in rest {
+        add_carried(&mut encrypted_sum, encrypted_value)?;
+    }
+    Ok(encrypted_sum)
+}
+
+fn tree_sum(values: Vec<LWE>) -> Result<LWE, String> {
+    tree(values, add_carried, |_| Ok(()))
+}
+
+// multiply a ciphertext by a float constant in [-1, 1], using all of its padding. The
+// ciphertext's interval must hold zero.
+fn scale(ciphertext: &mut LWE, constant: f64) -> Result<(), String> {
+    let max_constant: f64 = 1.;
+    let nb_bit_padding = mul_padding_bits(&ciphertext.encoder)?;
+    ciphertext.mul_constant_with_padding_inplace(constant, max_constant, nb_bit_padding)
+        .map_err(|e| format!("Failed to multiply encrypted value with a float value. {}", e))
+}
+
+/// Sum of the encrypted values, in an interval as many times larger as needed to hold it
+pub fn sum(values: &[LWE]) -> Result<LWE, String> {
+    let first = values.first().ok_or_else(|| String::from("No encrypted values"))?;
+    SumStrategy::choose(values.len(), &first.encoder, false, false)?;
+    padded_sum(values.to_vec())
+}
+
+/// Mean of the encrypted values, with the sum strategy chosen for their number.
+/// Bootstrapping is only used when given and when pre-scaling loses precision.
+pub fn mean(values: &[LWE], bootstrapping: Option<&Bootstrapping>) -> Result<LWE, String> {
+    let first = values.first().ok_or_else(|| String::from("No encrypted values"))?;
+    if values.len() == 1 {
+        return Ok(first.clone());
+    }
+    let length_multiplier = 1. / (values.len() as f64);
+    let strategy = SumStrategy::choose(values.len(), &first.encoder, true, bootstrapping.is_some())?;
+    tracing::debug!("Mean over {} encrypted values, sum strategy {:?}", values.len(), strategy);
+    let values_min = first.encoder.o;
+    let mut encrypted_mean = match (strategy, bootstrapping) {
+        (SumStrategy::PreScaled, _) => {
+            let mut scaled = values.to_vec();
+            for ciphertext in scaled.iter_mut() {
+                translate(ciphertext, -values_min)?;
+                scale(ciphertext, length_multiplier)?;
+            }
+            // every partial sum of the scaled values is in their interval
+            tree_sum(scaled)?
+        }
+        (SumStrategy::Bootstrapped, Some(bootstrapping)) => bootstrapping.mean(values)?,
+        (SumStrategy::Sequential, _) => divide(sequential_sum(values)?, length_multiplier)?,
+        _ => divide(tree_sum(values.to_vec())?, length_multiplier)?,
+    };
+    translate(&mut encrypted_mean, values_min)?;
+    if strategy != SumStrategy::Bootstrapped {
+        // 1/n is rounded to the padding bits, the error grows with the n values it multiplies
+        let precision = first.encoder.nb_bit_padding.saturating_sub(carry_bits(values.len()));
+        encrypted_mean.encoder.nb_bit_precision = encrypted_mean.encoder.nb_bit_precision.min(precision);
+    }
+    Ok(encrypted_mean)
+}
+
+// multiply a carried sum by 1/n, moving it to an interval from zero first so the multiplication
+// sees the carries. The result is in the interval from zero too.
+fn divide(mut encrypted_sum: LWE, length_multiplier: f64) -> Result<LWE, String> {
+    let sum_min = encrypted_sum.encoder.o;
+    translate(&mut encrypted_sum, -sum_min)?;
+    scale(&mut encrypted_sum, length_multiplier)?;
+    Ok(encrypted_sum)
+}
+
+// Parameters of the bootstrapping and key switching keys
+const BOOTSTRAP_BASE_LOG: usize = 6;
+const BOOTSTRAP_LEVEL: usize = 4;
+const KEYSWITCH_BASE_LOG: usize = 2;
+const KEYSWITCH_LEVEL: usize = 7;
+const RLWE_POLYNOMIAL_SIZE: usize = 1024;
+
+/// Keys to refresh the padding of a ciphertext by bootstrapping it. They are large and slow
+/// to generate, so sessions opt in with the bootstrap encryption parameter.
+pub struct Bootstrapping {
+    bootstrapping_key: LWEBSK,
+    // bootstrapped ciphertexts are under the RLWE key, this brings them back to the session key
+    keyswitching_key: LWEKSK,
+}
+
+impl Bootstrapping {
+    pub fn new(secret_key: &LWESecretKey) -> Self {
+        // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe_bsk/mod.rs
+        let rlwe_secret_key = RLWESecretKey::new(&RLWE80_1024_1);
+        let bootstrapped_secret_key = rlwe_secret_key.to_lwe_secret_key();
+        Self {
+            bootstrapping_key: LWEBSK::new(secret_key, &rlwe_secret_key, BOOTSTRAP_BASE_LOG, BOOTSTRAP_LEVEL),
+            keyswitching_key: LWEKSK::new(&bootstrapped_secret_key, secret_key, KEYSWITCH_BASE_LOG, KEYSWITCH_LEVEL),
+        }
+    }
+
+    /// approximate bytes held by the keys for a session key of `dimensions`
+    pub fn key_bytes(dimensions: usize) -> usize {
+        // per session key coefficient, level by 2x2 polynomials
+        let bootstrapping_key_bytes = dimensions * BOOTSTRAP_LEVEL * 4 * RLWE_POLYNOMIAL_SIZE * std::mem::size_of::<u64>();
+        bootstrapping_key_bytes + key_switching_key_bytes(RLWE_POLYNOMIAL_SIZE, dimensions)
+    }
+
+    // bootstrap a ciphertext evaluating `f` into `encoder`, then bring it back to the session key
+    fn apply(&self, ciphertext: &LWE, f: impl Fn(f64) -> f64, encoder: &Encoder) -> Result<LWE, String> {
+        // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs
+        // fn LWE::bootstrap_with_function(.., bsk, f, encoder_output) -> Result<LWE, CryptoAPIError>
+        let bootstrapped = ciphertext.bootstrap_with_function(&self.bootstrapping_key, f, encoder)
+            .map_err(|e| format!("Failed to bootstrap encrypted value. {}", e))?;
+        bootstrapped.keyswitch(&self.keyswitching_key)
+            .map_err(|e| format!("Failed to key switch bootstrapped value. {}", e))
+    }
+
+    // square a ciphertext while bootstrapping it, into the interval of the squares of its encoder's
+    fn square(&self, ciphertext: &LWE) -> Result<LWE, String> {
+        let encoder = &ciphertext.encoder;
+        let (squares_min, squares_max) = squares_interval(encoder.o, encoder.o + encoder.delta);
+        let squares_encoder = Encoder::new(squares_min, squares_max, encoder.nb_bit_precision, encoder.nb_bit_padding)
+            .map_err(|e| format!("Unable to instantiate squares encoder. {}", e))?;
+        self.apply(ciphertext, |x| x * x, &squares_encoder)
+    }
+
+    // Sum the values in groups small enough for their carries to leave a bit of padding clear,
+    // which the bootstrap needs, and bootstrap each partial sum into 1/n of its distance to
+    // the group's min. The result is the mean less the values' min.
+    fn mean(&self, values: &[LWE]) -> Result<LWE, String> {
+        let encoder = &values.first().ok_or_else(|| String::from("No encrypted values"))?.encoder;
+        let count = values.len() as f64;
+        let group_size = 1 << (mul_padding_bits(encoder)? - 1);
+        // every partial sum of the partial means is in it too
+        let means_encoder = Encoder::new(0., encoder.delta, encoder.nb_bit_precision, encoder.nb_bit_padding)
+            .map_err(|e| format!("Unable to instantiate means encoder. {}", e))?;
+        let partial_means = values.chunks(group_size)
+            .map(|group| {
+                let group_sum = padded_sum(group.to_vec())?;
+                let group_min = group_sum.encoder.o;
+                self.apply(&group_sum, |x| (x - group_min) / count, &means_encoder)
+            })
+            .collect::<Result<Vec<LWE>, String>>()?;
+        sequential_sum(&partial_means)
+    }
+}
+
+/// Encrypted squares of the encrypted values, each computed by a bootstrap evaluating x^2
+pub fn squares(values: &[LWE], bootstrapping: &Bootstrapping) -> Result<Vec<LWE>, String> {
+    values.iter().map(|ciphertext| bootstrapping.square(ciphertext)).collect()
+}
+
+/// dot product of the encrypted values with a plaintext vector of the same length
+pub fn dot(values: &[LWE], weights: &[f64]) -> Result<LWE, String> {
+    if values.len() != weights.len() {
+        return Err(format!("Expected {} weights, got {}", values.len(), weights.len()));
+    }
+    let max_constant = weights.iter().fold(0f64, |m, w| m.max(w.abs()));
+    if max_constant == 0. {
+        return Err(String::from("Weights are all zero"));
+    }
+    let first = values.first().ok_or_else(|| String::from("No encrypted values"))?;
+    // the weights get the padding the carries of the sum leave
+    let padding = first.encoder.nb_bit_padding;
+    let carries = carry_bits(values.len());
+    if carries >= padding {
+        return Err(format!(
+            "A weighted sum of {} values needs more than {} bits of padding, the values have {}. Raise encoder_padding_bits",
+            values.len(), carries, padding
+        ));
+    }
+    let mut scaled_values = Vec::with_capacity(values.len());
+    for (encrypted_value, weight) in values.iter().zip(weights) {
+        let mut scaled = encrypted_value.clone();
+        scaled.mul_constant_with_padding_inplace(*weight, max_constant, padding - carries)
+            .map_err(|e| format!("Failed to multiply encrypted value with a weight. {}", e))?;
+        scaled_values.push(scaled);
+    }
+    padded_sum(scaled_values)
+}
+
+/// mean weighted by non-negative per-value weights
+pub fn weighted_mean(values: &[LWE], weights: &[f64]) -> Result<LWE, String> {
+    if weights.iter().any(|w| *w < 0.) {
+        return Err(String::from("Weights must not be negative"));
+    }
+    let total: f64 = weights.iter().sum();
+    if total <= 0. {
+        return Err(String::from("Weights must not sum to zero"));
+    }
+    let normalized: Vec<f64> = weights.iter().map(|w| w / total).collect();
+    dot(values, &normalized)
+}
+
+/// variance from the decrypted means of the values and of their squares, E[x^2] - E[x]^2.
+/// Both means are computed over ciphertexts, only their combination is in the clear.
+pub fn variance(mean: Decrypted, mean_of_squares: Decrypted) -> Decrypted {
+    let value = (mean_of_squares.value - mean.value * mean.value).max(0.);
+    let error_bound = mean_of_squares.error_bound
+        + 2. * mean.value.abs() * mean.error_bound
+        + mean.error_bound * mean.error_bound;
+    Decrypted { value, error_bound, ..mean_of_squares }
+}
+
+/// interval covering the squares of values in `[min, max]`
+pub fn squares_interval(min: f64, max: f64) -> (f64, f64) {
+    let (low, high) = (min * min, max * max);
+    if min <= 0. && max >= 0. {
+        (0., low.max(high))
+    } else {
+        (low.min(high), low.max(high))
+    }
+}
+
+/// Decrypt every slot of a batch of ciphertexts
+pub fn decrypt_vector(ciphertexts: &VectorLWE, secret_key: &LWESecretKey) -> Result<Vec<Decrypted>, String> {
+    // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs
+    // fn VectorLWE::decrypt_decode(..) -> Result<Vec<f64>, CryptoAPIError>
+    let values = ciphertexts.decrypt_decode(secret_key)
+        .map_err(|e| format!("Failed to decrypt. {}", e))?;
+    Ok(values.into_iter()
+        .zip(ciphertexts.encoders.iter().zip(ciphertexts.variances.iter()))
+        .map(|(value, (encoder, variance))| Decrypted::new(value, encoder, *variance))
+        .collect())
+}
+
+fn check_vector_lengths(vectors: &[VectorLWE]) -> Result<&VectorLWE, String> {
+    let first = vectors.first().ok_or_else(|| String::from("No encrypted vectors"))?;
+    match vectors.iter().find(|vector| vector.nb_ciphertexts != first.nb_ciphertexts) {
+        Some(vector) => Err(format!("Expected vectors of length {}, got {}", first.nb_ciphertexts, vector.nb_ciphertexts)),
+        None => Ok(first),
+    }
+}
+
+fn first_encoder(vector: &VectorLWE) -> Result<&Encoder, String> {
+    vector.encoders.first().ok_or_else(|| String::from("Empty encrypted vector"))
+}
+
+/// slot by slot sum of batches of the same length, in intervals as many times larger as needed
+pub fn vector_sum(vectors: &[VectorLWE]) -> Result<VectorLWE, String> {
+    let first = check_vector_lengths(vectors)?;
+    SumStrategy::choose(vectors.len(), first_encoder(first)?, false, false)?;
+    tree(
+        vectors.to_vec(),
+        |left, right| left.add_with_padding_inplace(right)
+            .map_err(|e| format!("Failed to add two encrypted vectors. {}", e)),
+        |vector| vector.encoders.iter_mut().try_for_each(widen_encoder),
+    )
+}
+
+/// slot by slot mean of batches of the same length
+pub fn vector_mean(vectors: &[VectorLWE]) -> Result<VectorLWE, String> {
+    let first = check_vector_lengths(vectors)?;
+    let encoder = first_encoder(first)?;
+    // a sum carried into the padding then multiplied is as precise as scaling each vector first
+    SumStrategy::choose(vectors.len(), encoder, true, false)?;
+    let values_mins: Vec<f64> = first.encoders.iter().map(|encoder| encoder.o).collect();
+    let mut encrypted_mean = first.clone();
+    for encrypted_vector in &vectors[1..] {
+        // the mins add up, as for scalars, so no carry is lost
+        let new_min: Vec<f64> = encrypted_mean.encoders.iter().zip(encrypted_vector.encoders.iter())
+            .map(|(sum_encoder, encoder)| sum_encoder.o + encoder.o)
+            .collect();
+        encrypted_mean.add_with_new_min_inplace(encrypted_vector, &new_min)
+            .map_err(|e| format!("Failed to add two encrypted vectors. {}", e))?;
+    }
+    let sum_mins: Vec<f64> = encrypted_mean.encoders.iter().map(|encoder| -encoder.o).collect();
+    encrypted_mean.add_constant_dynamic_encoder_inplace(&sum_mins)
+        .map_err(|e| format!("Failed to add a constant to an encrypted vector. {}", e))?;
+    let length_multipliers = vec![1. / (vectors.len() as f64); encrypted_mean.nb_ciphertexts];
+    let max_constant: f64 = 1.;
+    let nb_bit_padding = mul_padding_bits(encoder)?;
+    encrypted_mean.mul_constant_with_padding_inplace(&length_multipliers, max_constant, nb_bit_padding)
+        .map_err(|e| format!("Failed to multiply encrypted vector sum with a float value. {}", e))?;
+    encrypted_mean.add_constant_dynamic_encoder_inplace(&values_mins)
+        .map_err(|e| format!("Failed to add a constant to an encrypted vector. {}", e))?;
+    let precision = nb_bit_padding.saturating_sub(carry_bits(vectors.len()));
+    for encoder in encrypted_mean.encoders.iter_mut() {
+        encoder.nb_bit_precision = encoder.nb_bit_precision.min(precision);
+    }
+    Ok(encrypted_mean)
+}
+
+/// approximate bytes held by a key switching key: per input key coefficient, level by output key ciphertexts
+pub fn key_switching_key_bytes(from_dimensions: usize, to_dimensions: usize) -> usize {
+    from_dimensions * KEYSWITCH_LEVEL * (to_dimensions + 1) * std::mem::size_of::<u64>()
+}
+
+/// Key switching key from one session key to another, for key rotation
+pub fn key_switching_key(from: &LWESecretKey, to: &LWESecretKey) -> LWEKSK {
+    // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe_ksk/mod.rs
+    LWEKSK::new(from, to, KEYSWITCH_BASE_LOG, KEYSWITCH_LEVEL)
+}
+
+pub fn switch_key(ciphertext: &LWE, key_switching_key: &LWEKSK) -> Result<LWE, String> {
+    ciphertext.keyswitch(key_switching_key)
+        .map_err(|e| format!("Failed to key switch encrypted value. {}", e))
+}
+
+pub fn switch_vector_key(ciphertexts: &VectorLWE, key_switching_key: &LWEKSK) -> Result<VectorLWE, String> {
+    ciphertexts.keyswitch(key_switching_key)
+        .map_err(|e| format!("Failed to key switch encrypted vector. {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoder(precision: usize, padding: usize) -> Encoder {
+        Encoder::new(0., 100., precision, padding).unwrap()
+    }
+
+    fn secret_key() -> LWESecretKey {
+        LWESecretKey::new(&LWEParams::new(1024, -40))
+    }
+
+    fn encrypt(secret_key: &LWESecretKey, values: &[f64], encoder: &Encoder) -> Vec<LWE> {
+        values.iter().map(|value| LWE::encode_encrypt(secret_key, *value, encoder).unwrap()).collect()
+    }
+
+    // the decrypted result is the plaintext one, within its own error bound
+    fn assert_decrypts_to(ciphertext: &LWE, secret_key: &LWESecretKey, expected: f64) {
+        let decrypted = decrypt(ciphertext, secret_key).unwrap();
+        assert!(
+            (decrypted.value - expected).abs() <= decrypted.error_bound,
+            "decrypted {}, expected {} within {}", decrypted.value, expected, decrypted.error_bound
+        );
+    }
+
+    #[test]
+    fn sums_spend_a_bit_of_padding_per_level() {
+        let encoder = encoder(16, 4);
+        assert_eq!(SumStrategy::choose(1, &encoder, false, false), Ok(SumStrategy::Tree));
+        assert_eq!(SumStrategy::choose(16, &encoder, false, false), Ok(SumStrategy::Tree));
+        assert!(SumStrategy::choose(17, &encoder, false, false).is_err());
+        // bootstrapping does not widen a sum
+        assert!(SumStrategy::choose(1000, &encoder, false, true).is_err());
+    }
+
+    #[test]
+    fn means_pre_scale_when_the_padding_covers_count_and_precision() {
+        let encoder = encoder(4, 8);
+        assert_eq!(SumStrategy::choose(16, &encoder, true, false), Ok(SumStrategy::PreScaled));
+        assert_eq!(SumStrategy::choose(17, &encoder, true, false), Ok(SumStrategy::Tree));
+        assert_eq!(SumStrategy::choose(17, &encoder, true, true), Ok(SumStrategy::Bootstrapped));
+        let above_zero = Encoder::new(1., 64., 4, 8).unwrap();
+        assert_eq!(SumStrategy::choose(16, &above_zero, true, false), Ok(SumStrategy::PreScaled));
+    }
+
+    #[test]
+    fn means_bootstrap_when_pre_scaling_loses_precision() {
+        assert_eq!(SumStrategy::choose(3, &encoder(16, 4), true, true), Ok(SumStrategy::Bootstrapped));
+        assert_eq!(SumStrategy::choose(1000, &encoder(16, 1), true, true), Ok(SumStrategy::Bootstrapped));
+    }
+
+    #[test]
+    fn means_fail_where_1_over_n_rounds_away() {
+        let encoder = encoder(16, 4);
+        assert_eq!(SumStrategy::choose(8, &encoder, true, false), Ok(SumStrategy::Sequential));
+        assert!(SumStrategy::choose(9, &encoder, true, false).is_err());
+        assert!(SumStrategy::choose(1000, &encoder, true, false).is_err());
+        // nothing multiplies without padding
+        assert!(SumStrategy::choose(3, &self::encoder(16, 0), true, true).is_err());
+    }
+
+    #[test]
+    fn sums_grow_their_interval_instead_of_wrapping() {
+        let secret_key = secret_key();
+        let encoder = encoder(8, 4);
+        assert_decrypts_to(&sum(&encrypt(&secret_key, &[60., 70.], &encoder)).unwrap(), &secret_key, 130.);
+        // the odd values move up the tree with the others
+        assert_decrypts_to(&sum(&encrypt(&secret_key, &[60., 70., 80., 90., 99.], &encoder)).unwrap(), &secret_key, 399.);
+        assert_decrypts_to(&sum(&encrypt(&secret_key, &[90.; 16], &encoder)).unwrap(), &secret_key, 1440.);
+        assert!(sum(&encrypt(&secret_key, &[90.; 17], &encoder)).is_err());
+    }
+
+    #[test]
+    fn means_match_the_plaintext_mean() {
+        let secret_key = secret_key();
+        let one_to_ten: Vec<f64> = (1..=10).map(|i| i as f64).collect();
+        // pre-scaled
+        let above_zero = Encoder::new(1., 64., 4, 8).unwrap();
+        assert_decrypts_to(&mean(&encrypt(&secret_key, &one_to_ten, &above_zero), None).unwrap(), &secret_key, 5.5);
+        let around_zero = Encoder::new(-50., 50., 4, 8).unwrap();
+        assert_decrypts_to(&mean(&encrypt(&secret_key, &[-40., -10., 20.], &around_zero), None).unwrap(), &secret_key, -10.);
+        // carried into the padding
+        let precise = Encoder::new(1., 64., 10, 8).unwrap();
+        assert_decrypts_to(&mean(&encrypt(&secret_key, &one_to_ten, &precise), None).unwrap(), &secret_key, 5.5);
+        let precise = Encoder::new(-50., 50., 8, 8).unwrap();
+        assert_decrypts_to(&mean(&encrypt(&secret_key, &[-40., -10., 20.], &precise), None).unwrap(), &secret_key, -10.);
+        let negative: Vec<f64> = (0..20).map(|i| -45. + (i % 7) as f64).collect();
+        assert_decrypts_to(&mean(&encrypt(&secret_key, &negative, &precise), None).unwrap(), &secret_key, negative.iter().sum::<f64>() / 20.);
+        assert_decrypts_to(&mean(&encrypt(&secret_key, &[42.], &above_zero), None).unwrap(), &secret_key, 42.);
+    }
+
+    #[test]
+    fn weighted_sums_match_the_plaintext_ones() {
+        let secret_key = secret_key();
+        let values = encrypt(&secret_key, &[10., 20., 30., 40.], &encoder(8, 8));
+        assert_decrypts_to(&dot(&values, &[0.5, 0.25, 1., 0.]).unwrap(), &secret_key, 40.);
+        assert_decrypts_to(&weighted_mean(&values, &[1., 1., 2., 0.]).unwrap(), &secret_key, 22.5);
+        assert!(dot(&encrypt(&secret_key, &[10.; 16], &encoder(8, 4)), &[1.; 16]).is_err());
+    }
+
+    #[test]
+    fn vector_sums_and_means_match_the_plaintext_ones() {
+        let secret_key = secret_key();
+        let encoder = Encoder::new(-50., 100., 8, 4).unwrap();
+        let vectors: Vec<VectorLWE> = [[60., -10.], [70., -20.], [80., -30.]].iter()
+            .map(|vector| VectorLWE::encode_encrypt(&secret_key, vector, &encoder).unwrap())
+            .collect();
+        let check = |encrypted: VectorLWE, expected: [f64; 2]| {
+            for (decrypted, expected) in decrypt_vector(&encrypted, &secret_key).unwrap().iter().zip(expected.iter()) {
+                assert!((decrypted.value - expected).abs() <= decrypted.error_bound, "decrypted {}, expected {}", decrypted.value, expected);
+            }
+        };
+        check(vector_sum(&vectors).unwrap(), [210., -60.]);
+        check(vector_mean(&vectors).unwrap(), [70., -20.]);
+    }
+
+    #[test]
+    fn bootstrapped_means_match_the_plaintext_mean() {
+        let secret_key = secret_key();
+        let bootstrapping = Bootstrapping::new(&secret_key);
+        // groups of 2 values, each bootstrapped into a tenth of its sum
+        let values: Vec<f64> = (0..10).map(|i| (i * 10) as f64).collect();
+        let encrypted = encrypt(&secret_key, &values, &encoder(4, 2));
+        assert_decrypts_to(&mean(&encrypted, Some(&bootstrapping)).unwrap(), &secret_key, 45.);
+        let squares = squares(&encrypted, &bootstrapping).unwrap();
+        assert_decrypts_to(&mean(&squares, Some(&bootstrapping)).unwrap(), &secret_key, 2850.);
+        let below_zero = Encoder::new(-50., 50., 4, 2).unwrap();
+        let encrypted = encrypt(&secret_key, &[-40., -30., -20., 0., 10.], &below_zero);
+        assert_decrypts_to(&mean(&encrypted, Some(&bootstrapping)).unwrap(), &secret_key, -16.);
+    }
+}
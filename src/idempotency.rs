@@ -0,0 +1,209 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, watch};
+use crate::session_common::{SenderSessionResponseChannel, SessionResponseStatus};
+use crate::utils::get_env_or;
+
+/// How long a session remembers the responses of actions sent with an idempotency key
+#[derive(Debug, Clone, Copy)]
+pub struct IdempotencyPolicy {
+    pub window_size: usize,
+    pub window: Duration,
+}
+
+impl IdempotencyPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            window_size: get_env_or("IDEMPOTENCY_WINDOW_SIZE", 1000),
+            window: Duration::from_secs(get_env_or("IDEMPOTENCY_WINDOW_SECS", 300)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Outcome {
+    Pending,
+    Done(SessionResponseStatus, String),
+    // the action never answered, a replay runs it again
+    Failed,
+}
+
+/// Responses of the recent keyed actions of a session, so a retried action is answered
+/// with its original response instead of being applied twice.
+pub struct IdempotencyCache {
+    policy: IdempotencyPolicy,
+    order: VecDeque<(Instant, String)>,
+    outcomes: HashMap<String, watch::Receiver<Outcome>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(policy: IdempotencyPolicy) -> Self {
+        Self { policy, order: VecDeque::new(), outcomes: HashMap::new() }
+    }
+
+    fn evict(&mut self, now: Instant) {
+        while let Some((added, key)) = self.order.front() {
+            let expired = now.saturating_duration_since(*added) > self.policy.window;
+            if !expired && self.order.len() < self.policy.window_size {
+                break;
+            }
+            self.outcomes.remove(key);
+            self.order.pop_front();
+        }
+    }
+
+    /// Answer a replayed action from the cache and return None, or return the channel the
+    /// session answers the action on. Keyed actions run to completion even when their caller
+    /// goes away, so its retry finds the response. Keys are scoped to the participant token
+    /// of the action, so in a group session a caller only replays its own admitted actions.
+    pub fn intercept(
+        &mut self,
+        sessionid: &str,
+        key: Option<&str>,
+        participant_token: Option<&str>,
+        resp: SenderSessionResponseChannel,
+    ) -> Option<SenderSessionResponseChannel> {
+        let (key, scoped_key) = match (key, participant_token) {
+            (None, _) => return Some(resp),
+            (Some(key), None) => (key, key.to_string()),
+            (Some(key), Some(participant_token)) => (key, format!("{}/{}", participant_token, key)),
+        };
+        let now = Instant::now();
+        self.evict(now);
+
+        if let Some(outcome_rx) = self.outcomes.get(&scoped_key) {
+            let replay = !matches!(*outcome_rx.borrow(), Outcome::Failed);
+            if replay {
+                tracing::debug!("[{}] Replaying response for idempotency key {}", sessionid, key);
+                tokio::spawn(replay_outcome(outcome_rx.clone(), resp));
+                return None;
+            }
+            self.outcomes.remove(&scoped_key);
+            self.order.retain(|(_, ordered_key)| *ordered_key != scoped_key);
+        }
+
+        let (outcome_tx, outcome_rx) = watch::channel(Outcome::Pending);
+        let (session_tx, session_rx) = oneshot::channel::<(SessionResponseStatus, String)>();
+        self.outcomes.insert(scoped_key.clone(), outcome_rx);
+        self.order.push_back((now, scoped_key));
+        tokio::spawn(async move {
+            match session_rx.await {
+                Ok((response_status, response_message)) => {
+                    let _ = outcome_tx.send(Outcome::Done(response_status.clone(), response_message.clone()));
+                    let _ = resp.send((response_status, response_message));
+                }
+                Err(_) => {
+                    let _ = outcome_tx.send(Outcome::Failed);
+                }
+            }
+        });
+        Some(session_tx)
+    }
+}
+
+// answer a replay once the original action is done
+async fn replay_outcome(mut outcome_rx: watch::Receiver<Outcome>, resp: SenderSessionResponseChannel) {
+    loop {
+        let outcome = outcome_rx.borrow().clone();
+        match outcome {
+            Outcome::Done(response_status, response_message) => {
+                let _ = resp.send((response_status, response_message));
+                return;
+            }
+            Outcome::Failed => return,
+            Outcome::Pending => {
+                if outcome_rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(window_size: usize, window: Duration) -> IdempotencyCache {
+        IdempotencyCache::new(IdempotencyPolicy { window_size, window })
+    }
+
+    // run a keyed action through the cache, answering it with `response` if the session gets it
+    async fn send(cache: &mut IdempotencyCache, key: Option<&str>, response: &str) -> (bool, String) {
+        send_as(cache, key, None, response).await
+    }
+
+    async fn send_as(cache: &mut IdempotencyCache, key: Option<&str>, participant_token: Option<&str>, response: &str) -> (bool, String) {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let ran = match cache.intercept("test", key, participant_token, resp_tx) {
+            Some(session_tx) => {
+                let _ = session_tx.send((SessionResponseStatus::SessionOk, response.to_string()));
+                true
+            }
+            None => false,
+        };
+        let (status, message) = resp_rx.await.unwrap();
+        assert!(matches!(status, SessionResponseStatus::SessionOk));
+        (ran, message)
+    }
+
+    #[tokio::test]
+    async fn replays_answer_with_the_original_response() {
+        let mut cache = cache(10, Duration::from_secs(60));
+        assert_eq!(send(&mut cache, Some("a"), "first").await, (true, String::from("first")));
+        assert_eq!(send(&mut cache, Some("a"), "second").await, (false, String::from("first")));
+        assert_eq!(send(&mut cache, Some("b"), "third").await, (true, String::from("third")));
+        // unkeyed actions always run
+        assert_eq!(send(&mut cache, None, "fourth").await, (true, String::from("fourth")));
+        assert_eq!(send(&mut cache, None, "fifth").await, (true, String::from("fifth")));
+    }
+
+    #[tokio::test]
+    async fn participants_only_replay_their_own_actions() {
+        let mut cache = cache(10, Duration::from_secs(60));
+        assert_eq!(send_as(&mut cache, Some("a"), Some("alice"), "alice's").await, (true, String::from("alice's")));
+        assert_eq!(send_as(&mut cache, Some("a"), Some("bob"), "bob's").await, (true, String::from("bob's")));
+        assert_eq!(send_as(&mut cache, Some("a"), None, "anyone's").await, (true, String::from("anyone's")));
+        assert_eq!(send_as(&mut cache, Some("a"), Some("alice"), "again").await, (false, String::from("alice's")));
+    }
+
+    #[tokio::test]
+    async fn replays_of_a_pending_action_wait_for_it() {
+        let mut cache = cache(10, Duration::from_secs(60));
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let session_tx = cache.intercept("test", Some("a"), None, resp_tx).unwrap();
+        let (replay_tx, replay_rx) = oneshot::channel();
+        assert!(cache.intercept("test", Some("a"), None, replay_tx).is_none());
+        session_tx.send((SessionResponseStatus::SessionOk, String::from("done"))).unwrap();
+        assert_eq!(resp_rx.await.unwrap().1, "done");
+        assert_eq!(replay_rx.await.unwrap().1, "done");
+    }
+
+    #[tokio::test]
+    async fn actions_that_never_answered_run_again() {
+        let mut cache = cache(10, Duration::from_secs(60));
+        let (resp_tx, resp_rx) = oneshot::channel();
+        drop(cache.intercept("test", Some("a"), None, resp_tx).unwrap());
+        assert!(resp_rx.await.is_err());
+        assert_eq!(send(&mut cache, Some("a"), "retried").await, (true, String::from("retried")));
+        assert_eq!(send(&mut cache, Some("a"), "again").await, (false, String::from("retried")));
+    }
+
+    #[tokio::test]
+    async fn oldest_keys_are_evicted_beyond_the_window_size() {
+        let mut cache = cache(2, Duration::from_secs(60));
+        send(&mut cache, Some("a"), "a").await;
+        send(&mut cache, Some("b"), "b").await;
+        send(&mut cache, Some("c"), "c").await;
+        assert_eq!(send(&mut cache, Some("c"), "c again").await, (false, String::from("c")));
+        assert_eq!(send(&mut cache, Some("a"), "a again").await, (true, String::from("a again")));
+    }
+
+    #[tokio::test]
+    async fn keys_expire_after_the_window() {
+        let mut cache = cache(10, Duration::from_millis(10));
+        send(&mut cache, Some("a"), "a").await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(send(&mut cache, Some("a"), "a again").await, (true, String::from("a again")));
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::prelude::*;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::session_common::SessionKind;
+
+// events kept for subscribers that fall behind
+const LIFECYCLE_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleEventKind {
+    Created,
+    InitFailed,
+    Exited,
+    Crashed,
+    Restarted,
+    // removed by its supervisor rather than by an exit action
+    Reaped,
+    // its secret key was exported, or it was created from an imported one
+    Migrated,
+    ServerShutdown,
+}
+
+/// Something that happened to a session, or to the server
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleEvent {
+    pub event: LifecycleEventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sessionid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<SessionKind>,
+    pub detail: String,
+    // the instance the event happened on, as in the x-sessionlocation header
+    pub location: String,
+    pub timestamp: String,
+}
+
+/// Server wide bus of lifecycle events, webhooks and other consumers subscribe to it
+#[derive(Debug, Clone)]
+pub struct LifecycleBus {
+    tx: broadcast::Sender<LifecycleEvent>,
+    location: String,
+}
+
+impl LifecycleBus {
+    pub fn new(location: String) -> Self {
+        let (tx, _) = broadcast::channel(LIFECYCLE_BUFFER);
+        Self { tx, location }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish(&self, event: LifecycleEventKind, sessionid: Option<&str>, kind: Option<SessionKind>, detail: &str) {
+        let event = LifecycleEvent {
+            event,
+            sessionid: sessionid.map(String::from),
+            kind,
+            detail: detail.to_string(),
+            location: self.location.clone(),
+            timestamp: Utc::now().to_rfc3339(),
+        };
+        tracing::debug!("Lifecycle event {:?}", event);
+        // sending only fails when nobody is subscribed
+        let _ = self.tx.send(event);
+    }
+
+    pub fn session(&self, event: LifecycleEventKind, sessionid: &str, kind: SessionKind, detail: &str) {
+        self.publish(event, Some(sessionid), Some(kind), detail);
+    }
+}
@@ -3,8 +3,8 @@ use axum::{
     body::Body,
     extract,
     response,
-    response::{IntoResponse}, 
-    http::{Request,StatusCode,header::{HeaderName,HeaderValue},Response},
+    response::{IntoResponse, sse}, 
+    http::{Request,StatusCode,HeaderMap,header::{HeaderName,HeaderValue},Response},
     AddExtensionLayer,
     Router,
     Json,
@@ -12,16 +12,17 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::Infallible,
     net::SocketAddr,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock, atomic::Ordering},
     time::Duration,
 };
 use tower::{BoxError, ServiceBuilder};
 use tower_http::{trace::TraceLayer};
 use chrono::prelude::*;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
 use lazy_static::lazy_static;
 
 mod session;
@@ -29,6 +30,34 @@ mod session_encrypted;
 mod session_common;
 use session_common::*;
 mod utils;
+mod capacity;
+use capacity::{Admission, CapacityLimits, CapacityReport};
+mod memory;
+use memory::{MemoryBudget, SessionMemory};
+mod crypto_pool;
+use crypto_pool::CryptoPool;
+mod supervisor;
+use supervisor::{FailedSessions, SupervisorPolicy};
+mod statistics;
+mod homomorphic;
+mod encryption_params;
+mod ciphertext_codec;
+mod wire_format;
+mod grpc;
+mod session_events;
+mod lifecycle;
+use lifecycle::{LifecycleBus, LifecycleEventKind};
+mod webhooks;
+mod idempotency;
+mod sequence;
+mod checkpoint;
+mod group;
+use group::ParticipantToken;
+use idempotency::IdempotencyPolicy;
+use session_events::{SessionEvent, SessionEvents};
+use wire_format::WireFormat;
+mod rolling;
+use rolling::RollingConfig;
 
 lazy_static! {
     /// This is an example for using doc comment attributes
@@ -42,15 +71,14 @@ struct ListSessionsResponse {
     sessionids: Vec<String>,
 }
 // Session action request/response messages
-#[derive(Deserialize)]
-struct SessionRequest {
-    message: String,
-}
 #[derive(Serialize)]
 struct SessionResponse {
     status: bool,
     message: String,
     sessionid: String,
+    // tokens of the participants of a group session, returned when it is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    participants: Option<Vec<ParticipantToken>>,
 }
 
 #[derive(Serialize)]
@@ -62,8 +90,181 @@ struct DebugResponse {
 // Shared state storing communication channel to sessions
 type SharedState = Arc<RwLock<State>>;
 struct State {
-    db: HashMap<String, session_common::SenderSessionRequestChannel>,
+    db: HashMap<String, SessionHandle>,
     shutdown_tx: tokio::sync::mpsc::Sender<()>,
+    limits: CapacityLimits,
+    // sessions admitted but still initializing, counted against capacity
+    reserved: HashMap<SessionKind, usize>,
+    memory_budget: Arc<MemoryBudget>,
+    crypto_pool: Arc<CryptoPool>,
+    command_policy: CommandPolicy,
+    supervisor_policy: SupervisorPolicy,
+    rolling: RollingConfig,
+    min_security_bits: u32,
+    key_export_token: Option<String>,
+    failed: FailedSessions,
+    session_panics: u64,
+    // distinguishes sessions reusing an id, so a stale supervisor never removes a newer session
+    next_instance: u64,
+    lifecycle: LifecycleBus,
+    idempotency: IdempotencyPolicy,
+    // session ids claimed by creations still initializing
+    creating: HashSet<String>,
+}
+
+// Everything the server keeps about a running session
+struct SessionHandle {
+    request_channel_tx: session_common::SenderSessionRequestChannel,
+    kind: SessionKind,
+    memory: SessionMemory,
+    instance: u64,
+    events: SessionEvents,
+    // a repeated create of the session must match it
+    init_message: String,
+    // key of the create request, whose replays get the participant tokens of a group again
+    idempotency_key: Option<String>,
+}
+
+impl State {
+    fn sessions_of_kind(&self, kind: SessionKind) -> usize {
+        let running = self.db.values().filter(|handle| handle.kind == kind).count();
+        running + self.reserved.get(&kind).copied().unwrap_or(0)
+    }
+
+    fn capacity_report(&self) -> CapacityReport {
+        CapacityReport::new(
+            &self.limits,
+            self.sessions_of_kind(SessionKind::Open),
+            self.sessions_of_kind(SessionKind::Encrypted),
+            self.memory_budget.used_bytes(),
+        )
+    }
+
+    // reserve room for a new session, to be released once its init completes
+    fn try_reserve(&mut self, kind: SessionKind) -> Admission {
+        let of_kind = self.sessions_of_kind(kind);
+        let total = self.sessions_of_kind(SessionKind::Open) + self.sessions_of_kind(SessionKind::Encrypted);
+        let admission = self.limits.admit(total, of_kind, kind, self.memory_budget.used_bytes());
+        if let Admission::Admitted = admission {
+            *self.reserved.entry(kind).or_insert(0) += 1;
+        }
+        admission
+    }
+
+    fn release_reservation(&mut self, kind: SessionKind) {
+        if let Some(count) = self.reserved.get_mut(&kind) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn new_session_config(&self) -> SessionConfig {
+        let snapshot = if self.supervisor_policy.restart_on_panic {
+            Some(Arc::new(Mutex::new(SessionSnapshot::default())))
+        } else {
+            None
+        };
+        SessionConfig {
+            max_values: self.limits.max_values_per_session,
+            memory: SessionMemory::new(self.memory_budget.clone()),
+            crypto_pool: self.crypto_pool.clone(),
+            snapshot,
+            rolling: self.rolling,
+            min_security_bits: self.min_security_bits,
+            key_export_token: self.key_export_token.clone(),
+            events: SessionEvents::new(),
+            lifecycle: self.lifecycle.clone(),
+            idempotency: self.idempotency,
+            max_checkpoints: self.limits.max_checkpoints_per_session,
+            seed: None,
+        }
+    }
+
+    // register a session, its id is no longer being created
+    fn insert_session(
+        &mut self,
+        sessionid: String,
+        request_channel_tx: SenderSessionRequestChannel,
+        kind: SessionKind,
+        session_config: &SessionConfig,
+        init_message: &str,
+        idempotency_key: Option<&str>,
+    ) -> u64 {
+        let instance = self.next_instance;
+        self.next_instance += 1;
+        let memory = session_config.memory.clone();
+        let events = session_config.events.clone();
+        self.creating.remove(&sessionid);
+        let init_message = init_message.to_string();
+        let idempotency_key = idempotency_key.map(String::from);
+        self.db.insert(sessionid, SessionHandle { request_channel_tx, kind, memory, instance, events, init_message, idempotency_key });
+        instance
+    }
+
+    // remove a session, unless the id has since been taken by another session
+    fn remove_session_instance(&mut self, sessionid: &str, instance: u64) -> bool {
+        let matches = match self.db.get(sessionid) {
+            Some(handle) => handle.instance == instance,
+            None => false,
+        };
+        if matches {
+            self.db.remove(sessionid);
+        }
+        matches
+    }
+}
+
+fn insert_retry_after<B>(response: &mut Response<B>, retry_after_secs: u64) {
+    response.headers_mut().insert(
+        HeaderName::from_static("retry-after"),
+        HeaderValue::from_str(retry_after_secs.to_string().as_str()).unwrap(),
+    );
+}
+
+// Encode a response body in the negotiated format
+fn wire_response<T: Serialize>(format: WireFormat, body: &T) -> Response<<Json<SessionResponse> as IntoResponse>::Body> {
+    match format.encode_response(body) {
+        Ok(bytes) => {
+            let mut response = Response::new(<<Json<SessionResponse> as IntoResponse>::Body>::from(bytes));
+            response.headers_mut().insert(
+                HeaderName::from_static("content-type"),
+                HeaderValue::from_static(format.content_type()),
+            );
+            response
+        }
+        Err(err_msg) => {
+            tracing::warn!("{}", err_msg);
+            let mut response = Response::new(<<Json<SessionResponse> as IntoResponse>::Body>::from(err_msg));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
+
+// Decode the message of a request body in its content type, structured messages as `M`,
+// or the error response to send back
+fn decode_wire_message<M: for<'de> Deserialize<'de> + Serialize>(
+    headers: &HeaderMap,
+    body: &[u8],
+    sessionid: &str,
+) -> Result<String, Box<Response<<Json<SessionResponse> as IntoResponse>::Body>>> {
+    let (status_code, err_msg) = match WireFormat::from_content_type(headers) {
+        Ok(format) => match format.decode_message::<M>(body) {
+            Ok(message) => return Ok(message),
+            Err(e) => (StatusCode::BAD_REQUEST, e),
+        },
+        Err(e) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, e),
+    };
+    let err_msg = format!("[{}] Failure. {}", sessionid, err_msg);
+    tracing::warn!("{}", err_msg);
+    let error_response = SessionResponse {
+        status: false,
+        message: err_msg,
+        sessionid: sessionid.to_string(),
+        participants: None,
+    };
+    let mut response = wire_response(WireFormat::from_accept(headers), &error_response);
+    *response.status_mut() = status_code;
+    Err(Box::new(response))
 }
 
 // basic handler that responds with a static string
@@ -111,128 +312,464 @@ async fn list_sessions(
     response::Json(list_sessions_response)
 }
 
+// remaining session capacity, polled by the load balancer
+async fn capacity_handler(
+    extract::Extension(state): extract::Extension<SharedState>,
+) -> impl IntoResponse {
+    let capacity_report = state.read().unwrap().capacity_report();
+    let mut response: Response<<Json<CapacityReport> as IntoResponse>::Body> = Json(capacity_report).into_response();
+    response.headers_mut().insert(
+        HeaderName::from_static("x-sessionlocation"),
+        HeaderValue::from_str(LOCALIP.as_str()).unwrap(),
+    );
+    response
+}
+
+// Session introspection response message
+#[derive(Serialize)]
+struct SessionInfoResponse {
+    status: bool,
+    message: String,
+    sessionid: String,
+    kind: Option<SessionKind>,
+    memory_bytes: usize,
+    failure: Option<String>,
+}
+
+async fn session_info(
+    extract::Path(sessionid): extract::Path<String>,
+    headers: HeaderMap,
+    extract::Extension(state): extract::Extension<SharedState>,
+) -> impl IntoResponse {
+    tracing::debug!("[{}] session_info request received", sessionid);
+    let mut info_response = SessionInfoResponse {
+        status: true,
+        message: String::from("Ok"),
+        sessionid: sessionid.clone(),
+        kind: None,
+        memory_bytes: 0,
+        failure: None,
+    };
+    let mut status_code = StatusCode::OK;
+    let shared_state = state.read().unwrap();
+    match shared_state.db.get(&sessionid) {
+        Some(handle) => {
+            info_response.kind = Some(handle.kind);
+            info_response.memory_bytes = handle.memory.held_bytes();
+        }
+        None => {
+            let failure = shared_state.failed.get(&sessionid).cloned();
+            let err_msg = match &failure {
+                Some(failure) => format!("[{}] Failure. Session failed. {}", sessionid, failure),
+                None => format!("[{}] Failure. Session not found", sessionid),
+            };
+            tracing::warn!("{}", err_msg);
+            status_code = if failure.is_some() { StatusCode::GONE } else { StatusCode::NOT_FOUND };
+            info_response.status = false;
+            info_response.message = err_msg;
+            info_response.failure = failure;
+        }
+    }
+    drop(shared_state);
+    let mut response = wire_response(WireFormat::from_accept(&headers), &info_response);
+    *response.status_mut() = status_code;
+    response
+}
+
+// Prometheus text format metrics
+async fn metrics_handler(
+    extract::Extension(state): extract::Extension<SharedState>,
+) -> String {
+    let shared_state = state.read().unwrap();
+    let mut metrics = String::new();
+    metrics.push_str("# TYPE stickyapp_sessions gauge\n");
+    for kind in &[SessionKind::Open, SessionKind::Encrypted] {
+        metrics.push_str(format!("stickyapp_sessions{{kind=\"{:?}\"}} {}\n", kind, shared_state.sessions_of_kind(*kind)).as_str());
+    }
+    metrics.push_str("# TYPE stickyapp_memory_budget_bytes gauge\n");
+    metrics.push_str(format!("stickyapp_memory_budget_bytes {}\n", shared_state.memory_budget.budget_bytes()).as_str());
+    metrics.push_str("# TYPE stickyapp_memory_used_bytes gauge\n");
+    metrics.push_str(format!("stickyapp_memory_used_bytes {}\n", shared_state.memory_budget.used_bytes()).as_str());
+    metrics.push_str("# TYPE stickyapp_session_memory_bytes gauge\n");
+    for (sessionid, handle) in &shared_state.db {
+        metrics.push_str(format!("stickyapp_session_memory_bytes{{sessionid=\"{}\"}} {}\n", sessionid, handle.memory.held_bytes()).as_str());
+    }
+    metrics.push_str("# TYPE stickyapp_session_panics_total counter\n");
+    metrics.push_str(format!("stickyapp_session_panics_total {}\n", shared_state.session_panics).as_str());
+    let crypto_stats = shared_state.crypto_pool.stats();
+    metrics.push_str("# TYPE stickyapp_crypto_workers gauge\n");
+    metrics.push_str(format!("stickyapp_crypto_workers {}\n", shared_state.crypto_pool.workers()).as_str());
+    metrics.push_str("# TYPE stickyapp_crypto_jobs_queued gauge\n");
+    metrics.push_str(format!("stickyapp_crypto_jobs_queued {}\n", crypto_stats.queued.load(Ordering::Relaxed)).as_str());
+    metrics.push_str("# TYPE stickyapp_crypto_jobs_running gauge\n");
+    metrics.push_str(format!("stickyapp_crypto_jobs_running {}\n", crypto_stats.running.load(Ordering::Relaxed)).as_str());
+    metrics.push_str("# TYPE stickyapp_crypto_jobs_completed_total counter\n");
+    metrics.push_str(format!("stickyapp_crypto_jobs_completed_total {}\n", crypto_stats.completed.load(Ordering::Relaxed)).as_str());
+    metrics.push_str("# TYPE stickyapp_crypto_jobs_cancelled_total counter\n");
+    metrics.push_str(format!("stickyapp_crypto_jobs_cancelled_total {}\n", crypto_stats.cancelled.load(Ordering::Relaxed)).as_str());
+    metrics.push_str("# TYPE stickyapp_crypto_queue_wait_microseconds_total counter\n");
+    metrics.push_str(format!("stickyapp_crypto_queue_wait_microseconds_total {}\n", crypto_stats.queue_wait_micros.load(Ordering::Relaxed)).as_str());
+    metrics
+}
+
 #[derive(Deserialize)]
 struct SessionRequestQuery {
     encrypted: Option<bool>,
+    // client chosen session id
+    sessionid: Option<String>,
 }
 impl Default for SessionRequestQuery {
     fn default() -> Self { 
-        Self {encrypted: Some(false)}
+        Self {encrypted: Some(false), sessionid: None}
     }
 }
-async fn create_session(
-    extract::Json(create_request): extract::Json<SessionRequest>,
-    extract::Extension(state): extract::Extension<SharedState>,
-    session_query: extract::Query<SessionRequestQuery>
-) -> impl IntoResponse {
-    let need_encrypted_session: bool = match session_query.encrypted {
-        Some(e) => e,
-        None => false
-    };
-    let sessionid = new_session_id(need_encrypted_session);
-    tracing::debug!("[{}] Trying, Session creation. Encrypted: {}", sessionid, need_encrypted_session);
+// Result of creating a session, rejected ones carry an error status code
+struct SessionCreateOutcome {
+    status_code: StatusCode,
+    response: SessionResponse,
+    retry_after_secs: u64,
+}
+
+// The session id asked for by a create request: chosen by the client, derived from its
+// idempotency key, or random. Repeated creates with the same id find the existing session.
+fn requested_session_id(session_kind: SessionKind, sessionid: Option<String>, idempotency_key: Option<&str>) -> Result<String, String> {
+    match (sessionid, idempotency_key) {
+        (Some(sessionid), _) => check_session_id(&sessionid).map(|_| sessionid),
+        (None, Some(idempotency_key)) => Ok(keyed_session_id(idempotency_key)),
+        (None, None) => Ok(new_session_id(session_kind)),
+    }
+}
+
+async fn execute_create_session(
+    state: &SharedState,
+    session_kind: SessionKind,
+    message: String,
+    sessionid: String,
+    idempotency_key: Option<&str>,
+    seed: Option<SeedSlot>,
+) -> SessionCreateOutcome {
+    tracing::debug!("[{}] Trying, Session creation. Kind: {:?}", sessionid, session_kind);
     let localip = LOCALIP.clone();
     let mut create_response = SessionResponse {
         status: true,
         message: String::from(""),
         sessionid: sessionid.clone(),
+        participants: None,
     }; 
 
+    // a retried create gets the session it created before, a different create for the id a conflict.
+    // Only a replay of the create's idempotency key is the original caller, to hand the tokens to.
+    let existing = {
+        let mut shared_state = state.write().unwrap();
+        let existing_handle = shared_state.db.get(&sessionid).map(|handle| (
+            handle.kind,
+            handle.init_message == message,
+            idempotency_key.is_some() && handle.idempotency_key.as_deref() == idempotency_key,
+        ));
+        match existing_handle {
+            Some((kind, true, replay)) if kind == session_kind => Some(Ok(replay)),
+            Some((kind, _, _)) => Some(Err(format!(
+                "Session exists as a {:?} session with a different init message", kind
+            ))),
+            None if !shared_state.creating.insert(sessionid.clone()) => Some(Err(String::from(
+                "Session is being created by another request, retry"
+            ))),
+            None => None,
+        }
+    };
+    match existing {
+        Some(Ok(replay)) => {
+            tracing::info!("[{}] Session already exists, returning it", sessionid);
+            create_response.message = format!("[{}] Session already exists", sessionid);
+            if replay {
+                create_response.participants = group::participant_tokens(&sessionid, &message);
+            }
+            return SessionCreateOutcome { status_code: StatusCode::OK, response: create_response, retry_after_secs: 0 };
+        }
+        Some(Err(err_msg)) => {
+            let err_msg = format!("[{}] Failure while creating session. {}", sessionid, err_msg);
+            tracing::warn!("{}", err_msg);
+            create_response.status = false;
+            create_response.message = err_msg;
+            return SessionCreateOutcome { status_code: StatusCode::CONFLICT, response: create_response, retry_after_secs: 0 };
+        }
+        None => {}
+    }
+
+    // check capacity before spawning anything
+    let (admission, retry_after_secs, command_policy, mut session_config, lifecycle) = {
+        let mut shared_state = state.write().unwrap();
+        (
+            shared_state.try_reserve(session_kind),
+            shared_state.limits.retry_after_secs,
+            shared_state.command_policy,
+            shared_state.new_session_config(),
+            shared_state.lifecycle.clone(),
+        )
+    };
+    let rejected_status = match admission {
+        Admission::Admitted => None,
+        Admission::InstanceFull | Admission::MemoryExhausted => Some(StatusCode::SERVICE_UNAVAILABLE),
+        Admission::KindFull => Some(StatusCode::TOO_MANY_REQUESTS),
+    };
+    if let Some(status_code) = rejected_status {
+        state.write().unwrap().creating.remove(&sessionid);
+        let err_msg = format!("[{}] Failure while creating session. No capacity left for {:?} sessions ({:?})", sessionid, session_kind, admission);
+        tracing::warn!("{}", err_msg);
+        create_response.status = false;
+        create_response.message = err_msg;
+        return SessionCreateOutcome { status_code, response: create_response, retry_after_secs };
+    }
+
+    session_config.seed = seed;
+
     // create the main channel for communicating with session
     let (request_channel_tx, request_channel_rx) =
-        tokio::sync::mpsc::channel::<(SessionRequestCommand, SenderSessionResponseChannel)>(100);
+        tokio::sync::mpsc::channel::<(SessionRequestCommand, SenderSessionResponseChannel)>(command_policy.queue_size);
     
     // create a one-time channel to check if the session started correctly
     let (init_success_tx, init_success_rx) = tokio::sync::oneshot::channel::<(SessionResponseStatus, String)>();
 
-    if need_encrypted_session {
-        session_encrypted::spawn(
-            sessionid.clone(), 
-            create_request.message, 
-            request_channel_rx, 
-            init_success_tx
-        ).await;
-    } else {
-        session::spawn(
-            sessionid.clone(), 
-            create_request.message, 
-            request_channel_rx, 
-            init_success_tx
-        ).await;
-    }
+    let join_handle = spawn_session(
+        session_kind,
+        sessionid.clone(), 
+        message.clone(), 
+        request_channel_rx, 
+        init_success_tx,
+        session_config.clone(),
+    ).await;
 
     let session_spawned = wait_for_init(sessionid.as_str(), init_success_rx).await;
+    state.write().unwrap().release_reservation(session_kind);
     match session_spawned {
         Ok(init_response) => {
-            {
-                // Add the main communication channel with the session into shared state
-                //state.write().unwrap().db.insert(sessionid.clone(), request_channel_tx);
-                let mut shared_state = state.write().unwrap();
-                shared_state.db.insert(sessionid.clone(), request_channel_tx);
-                //localip = shared_state.localip.clone();
-            }
+            create_response.participants = group::participant_tokens(&sessionid, &message);
+            let imports_key = session_kind == SessionKind::Encrypted && encryption_params::imports_key(&message);
+            // Add the main communication channel with the session into shared state
+            let instance = state.write().unwrap().insert_session(
+                sessionid.clone(), request_channel_tx, session_kind, &session_config, &message, idempotency_key
+            );
+            tokio::spawn(supervise_session(
+                state.clone(),
+                sessionid.clone(),
+                instance,
+                session_kind,
+                message,
+                session_config,
+                join_handle,
+            ));
             tracing::info!("[{}] Success, Session created at {}. {}", sessionid, localip, init_response);
+            lifecycle.session(LifecycleEventKind::Created, &sessionid, session_kind, &init_response);
+            if imports_key {
+                lifecycle.session(LifecycleEventKind::Migrated, &sessionid, session_kind, "Secret key imported");
+            }
             create_response.message = init_response;
         }
         Err(error_msg) => {
+            state.write().unwrap().creating.remove(&sessionid);
             let err_msg = format!("[{}] Failure while creating session. {}", sessionid, error_msg);
             tracing::warn!("{}", err_msg);
+            lifecycle.session(LifecycleEventKind::InitFailed, &sessionid, session_kind, &error_msg);
             create_response.status = false;
             create_response.message = err_msg
         }
     }
-    let mut response: Response<<Json<SessionResponse> as IntoResponse>::Body> = Json(create_response).into_response();
-    response.headers_mut().insert(
-        HeaderName::from_static("x-sessionid"),
-        HeaderValue::from_str(sessionid.as_str()).unwrap(),
-    );
+    SessionCreateOutcome { status_code: StatusCode::OK, response: create_response, retry_after_secs }
+}
+
+async fn create_session(
+    headers: HeaderMap,
+    body: bytes::Bytes,
+    extract::Extension(state): extract::Extension<SharedState>,
+    session_query: extract::Query<SessionRequestQuery>
+) -> impl IntoResponse {
+    let need_encrypted_session: bool = session_query.encrypted.unwrap_or_default();
+    let session_kind = if need_encrypted_session { SessionKind::Encrypted } else { SessionKind::Open };
+    let response_format = WireFormat::from_accept(&headers);
+    // init messages are free form
+    let init_message = match decode_wire_message::<serde_json::Value>(&headers, &body, "") {
+        Ok(message) => message,
+        Err(response) => return *response,
+    };
+    let idempotency_key = headers.get("idempotency-key").and_then(|key| key.to_str().ok());
+    let sessionid = match requested_session_id(session_kind, session_query.sessionid.clone(), idempotency_key) {
+        Ok(sessionid) => sessionid,
+        Err(err_msg) => {
+            let err_msg = format!("Failure while creating session. {}", err_msg);
+            tracing::warn!("{}", err_msg);
+            let create_response = SessionResponse { status: false, message: err_msg, sessionid: String::new(), participants: None };
+            let mut response = wire_response(response_format, &create_response);
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+
+    let outcome = execute_create_session(&state, session_kind, init_message, sessionid, idempotency_key, None).await;
+    session_create_response(outcome, response_format)
+}
+
+fn session_create_response(outcome: SessionCreateOutcome, format: WireFormat) -> Response<<Json<SessionResponse> as IntoResponse>::Body> {
+    let mut response = wire_response(format, &outcome.response);
+    *response.status_mut() = outcome.status_code;
+    if outcome.status_code == StatusCode::TOO_MANY_REQUESTS || outcome.status_code == StatusCode::SERVICE_UNAVAILABLE {
+        insert_retry_after(&mut response, outcome.retry_after_secs);
+    } else if outcome.status_code == StatusCode::OK {
+        response.headers_mut().insert(
+            HeaderName::from_static("x-sessionid"),
+            HeaderValue::from_str(outcome.response.sessionid.as_str()).unwrap(),
+        );
+    }
     response.headers_mut().insert(
         HeaderName::from_static("x-sessionlocation"),
-        HeaderValue::from_str(localip.as_str()).unwrap(),
+        HeaderValue::from_str(LOCALIP.as_str()).unwrap(),
     );
     response
 }
 
-async fn session_action(
-    extract::Path(sessionid): extract::Path<String>,
-    extract::Json(action_request): extract::Json<SessionRequest>,
+// Create a session on this instance starting from a copy of another session's state.
+// The fork has the source's kind and init message, so a retried keyed fork finds it.
+async fn execute_fork_session(state: &SharedState, source_sessionid: &str, sessionid: String) -> SessionCreateOutcome {
+    let (source, retry_after_secs, command_policy) = {
+        let shared_state = state.read().unwrap();
+        let source = shared_state.db.get(source_sessionid)
+            .map(|handle| (handle.request_channel_tx.clone(), handle.kind, handle.init_message.clone()));
+        (source, shared_state.limits.retry_after_secs, shared_state.command_policy)
+    };
+    let mut fork_response = SessionResponse {
+        status: false,
+        message: String::from(""),
+        sessionid: sessionid.clone(),
+        participants: None,
+    };
+    let (request_channel_tx, session_kind, init_message) = match source {
+        Some(source) => source,
+        None => {
+            fork_response.message = format!("[{}] Failure while forking session. Session {} not found", sessionid, source_sessionid);
+            tracing::warn!("{}", fork_response.message);
+            return SessionCreateOutcome { status_code: StatusCode::NOT_FOUND, response: fork_response, retry_after_secs };
+        }
+    };
+
+    let seed: SeedSlot = Arc::new(Mutex::new(None));
+    let command_response = session_common::send_command(
+        source_sessionid, request_channel_tx,
+        SessionRequestCommand::SessionFork(seed.clone()),
+        command_policy,
+    ).await;
+    let source_response = match command_response {
+        Ok((_, source_response)) => source_response,
+        Err(command_error) => {
+            let status_code = match command_error {
+                CommandError::QueueFull(_) => StatusCode::TOO_MANY_REQUESTS,
+                CommandError::ExecutionTimeout => StatusCode::GATEWAY_TIMEOUT,
+                // the session went away while forking, as for a failed session
+                CommandError::Failed(_) => StatusCode::GONE,
+            };
+            fork_response.message = format!("[{}] Failure while forking session {}. {}", sessionid, source_sessionid, command_error);
+            tracing::warn!("{}", fork_response.message);
+            return SessionCreateOutcome { status_code, response: fork_response, retry_after_secs };
+        }
+    };
+    // a session that can not be forked leaves the slot empty and says why
+    if seed.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+        fork_response.message = format!("[{}] Failure while forking session {}. {}", sessionid, source_sessionid, source_response);
+        tracing::warn!("{}", fork_response.message);
+        return SessionCreateOutcome { status_code: StatusCode::BAD_REQUEST, response: fork_response, retry_after_secs };
+    }
+    tracing::info!("[{}] Forking session {}", sessionid, source_sessionid);
+    execute_create_session(state, session_kind, init_message, sessionid, None, Some(seed)).await
+}
+
+async fn fork_session(
+    extract::Path(source_sessionid): extract::Path<String>,
+    headers: HeaderMap,
     extract::Extension(state): extract::Extension<SharedState>,
+    session_query: extract::Query<SessionRequestQuery>
 ) -> impl IntoResponse {
-    tracing::debug!("[{}] session_action request received", sessionid);
+    tracing::debug!("[{}] fork_session request received", source_sessionid);
+    let response_format = WireFormat::from_accept(&headers);
+    let source_kind = state.read().unwrap().db.get(&source_sessionid).map(|handle| handle.kind);
+    let idempotency_key = headers.get("idempotency-key").and_then(|key| key.to_str().ok());
+    // the fork takes the kind of its source, an unknown source is reported by the fork itself
+    let sessionid = match requested_session_id(source_kind.unwrap_or(SessionKind::Open), session_query.sessionid.clone(), idempotency_key) {
+        Ok(sessionid) => sessionid,
+        Err(err_msg) => {
+            let err_msg = format!("Failure while forking session. {}", err_msg);
+            tracing::warn!("{}", err_msg);
+            let fork_response = SessionResponse { status: false, message: err_msg, sessionid: String::new(), participants: None };
+            let mut response = wire_response(response_format, &fork_response);
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+    let outcome = execute_fork_session(&state, &source_sessionid, sessionid).await;
+    session_create_response(outcome, response_format)
+}
 
+// Result of forwarding an action message to a session
+struct SessionActionOutcome {
+    status_code: StatusCode,
+    response: SessionResponse,
+    queue_depth: Option<usize>,
+    retry_after_secs: u64,
+}
+
+async fn execute_session_action(state: &SharedState, sessionid: &str, message: String) -> SessionActionOutcome {
     // Access shared state to extract session info
-    let session_info = 
+    let (session_info, session_failure, retry_after_secs, command_policy) = 
     {
-        let db = &state.read().unwrap().db;
-        match db.get(&sessionid) {
-            Some(session_channel) => Some(session_channel.clone()),
-            None => None, 
-        }
+        let shared_state = state.read().unwrap();
+        // the instance tells this session apart from one created again under its id
+        let session_channel = shared_state.db.get(sessionid)
+            .map(|handle| (handle.request_channel_tx.clone(), handle.instance));
+        let session_failure = shared_state.failed.get(sessionid).cloned();
+        (session_channel, session_failure, shared_state.limits.retry_after_secs, shared_state.command_policy)
     };
+    let mut queue_depth: Option<usize> = None;
+    let mut status_code = StatusCode::OK;
 
     // Create an empty response
     let mut action_response = SessionResponse {
         status: true,
         message: String::from(""),
-        sessionid: sessionid.clone(),
+        sessionid: sessionid.to_string(),
+        participants: None,
     };
 
     match session_info {
-        Some(request_channel_tx) => {
+        Some((request_channel_tx, instance)) => {
             let command_response = session_common::send_command(
-                &sessionid, request_channel_tx, 
-                session_common::SessionRequestCommand::SessionCommand(action_request.message)
+                sessionid, request_channel_tx, 
+                session_common::SessionRequestCommand::SessionCommand(message),
+                command_policy,
             ).await;
             match command_response {
                 Ok((response_status,response_message)) => {
                     action_response.message = response_message;
-                    if let session_common::SessionResponseStatus::SessionExit = response_status {
-                        state.write().unwrap().db.remove(&sessionid);
-                        tracing::info!("[{}] Removing session", sessionid);
+                    match response_status {
+                        session_common::SessionResponseStatus::SessionExit => {
+                            if state.write().unwrap().remove_session_instance(sessionid, instance) {
+                                tracing::info!("[{}] Removing session", sessionid);
+                            }
+                        }
+                        session_common::SessionResponseStatus::SessionFull => {
+                            action_response.status = false;
+                            status_code = StatusCode::TOO_MANY_REQUESTS;
+                        }
+                        session_common::SessionResponseStatus::SessionOk => {}
                     }
                 }
-                Err(err_str) => {
-                    let err_msg = format!("[{}] Failure executing session command. {}", sessionid, err_str);
+                Err(command_error) => {
+                    match command_error {
+                        CommandError::QueueFull(depth) => {
+                            status_code = StatusCode::TOO_MANY_REQUESTS;
+                            queue_depth = Some(depth);
+                        }
+                        CommandError::ExecutionTimeout => status_code = StatusCode::GATEWAY_TIMEOUT,
+                        // the session went away, as for a failed session
+                        CommandError::Failed(_) => status_code = StatusCode::GONE,
+                    }
+                    let err_msg = format!("[{}] Failure executing session command. {}", sessionid, command_error);
                     tracing::warn!("{}", err_msg);
                     action_response.status = false;
                     action_response.message = err_msg;
@@ -240,13 +777,323 @@ async fn session_action(
             }
         }
         None => {
-            let err_msg = format!("[{}] Failure. Session not found", sessionid);
+            let err_msg = match session_failure {
+                Some(failure) => {
+                    status_code = StatusCode::GONE;
+                    format!("[{}] Failure. Session failed. {}", sessionid, failure)
+                }
+                None => format!("[{}] Failure. Session not found", sessionid),
+            };
             tracing::warn!("{}", err_msg);
             action_response.status = false;
             action_response.message = err_msg;
         }
     }
-    response::Json(action_response)
+    SessionActionOutcome { status_code, response: action_response, queue_depth, retry_after_secs }
+}
+
+fn session_action_response(outcome: SessionActionOutcome, format: WireFormat) -> Response<<Json<SessionResponse> as IntoResponse>::Body> {
+    let mut response = wire_response(format, &outcome.response);
+    *response.status_mut() = outcome.status_code;
+    if outcome.status_code == StatusCode::TOO_MANY_REQUESTS {
+        insert_retry_after(&mut response, outcome.retry_after_secs);
+    }
+    if let Some(depth) = outcome.queue_depth {
+        response.headers_mut().insert(
+            HeaderName::from_static("x-session-queue-depth"),
+            HeaderValue::from_str(depth.to_string().as_str()).unwrap(),
+        );
+    }
+    response
+}
+
+// Add the idempotency key of a request to its action message, a key in the message itself wins
+fn with_idempotency_key(message: String, idempotency_key: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(&message) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            // structured messages come with every field, unset ones null
+            let key = fields.entry("idempotency_key").or_insert(serde_json::Value::Null);
+            if key.is_null() {
+                *key = serde_json::Value::from(idempotency_key);
+            }
+            serde_json::Value::Object(fields).to_string()
+        }
+        // the session reports messages it cannot decode
+        _ => message,
+    }
+}
+
+async fn session_action(
+    extract::Path(sessionid): extract::Path<String>,
+    headers: HeaderMap,
+    body: bytes::Bytes,
+    extract::Extension(state): extract::Extension<SharedState>,
+) -> impl IntoResponse {
+    tracing::debug!("[{}] session_action request received", sessionid);
+    let message = match decode_wire_message::<SessionRequestMessage>(&headers, &body, &sessionid) {
+        Ok(message) => message,
+        Err(response) => return *response,
+    };
+    let message = match headers.get("idempotency-key").and_then(|key| key.to_str().ok()) {
+        Some(idempotency_key) => with_idempotency_key(message, idempotency_key),
+        None => message,
+    };
+    let outcome = execute_session_action(&state, &sessionid, message).await;
+    session_action_response(outcome, WireFormat::from_accept(&headers))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    // observers of a group session are its participants
+    participant_token: Option<String>,
+}
+
+// Server-sent events of a session, each observer gets its own subscription until the session exits
+async fn events_handler(
+    extract::Path(sessionid): extract::Path<String>,
+    extract::Extension(state): extract::Extension<SharedState>,
+    events_query: extract::Query<EventsQuery>,
+) -> Result<
+    sse::Sse<tokio_stream::wrappers::ReceiverStream<Result<sse::Event, Infallible>>>,
+    Response<<Json<SessionResponse> as IntoResponse>::Body>,
+> {
+    tracing::debug!("[{}] events request received", sessionid);
+    let events_rx = state.read().unwrap().db.get(&sessionid).map(|handle| {
+        if group::admits_observer(&sessionid, &handle.init_message, events_query.participant_token.as_deref()) {
+            Ok(handle.events.subscribe())
+        } else {
+            Err((StatusCode::FORBIDDEN, "Group sessions need the participant_token of a participant"))
+        }
+    });
+    let mut events_rx = match events_rx.unwrap_or(Err((StatusCode::NOT_FOUND, "Session not found"))) {
+        Ok(events_rx) => events_rx,
+        Err((status_code, err_msg)) => {
+            let err_msg = format!("[{}] Failure. {}", sessionid, err_msg);
+            tracing::warn!("{}", err_msg);
+            let mut response: Response<<Json<SessionResponse> as IntoResponse>::Body> = Json(SessionResponse {
+                status: false,
+                message: err_msg,
+                sessionid,
+                participants: None,
+            }).into_response();
+            *response.status_mut() = status_code;
+            return Err(response);
+        }
+    };
+    let (sse_tx, sse_rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let (event, exiting) = match events_rx.recv().await {
+                Ok(session_event) => {
+                    let exiting = matches!(session_event, SessionEvent::SessionExiting { .. });
+                    let data = serde_json::to_string(&session_event).unwrap_or_default();
+                    (sse::Event::default().event(session_event.name()).data(data), exiting)
+                }
+                // a slow observer missed events, tell it how many
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    (sse::Event::default().event("lagged").data(skipped.to_string()), false)
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            };
+            if sse_tx.send(Ok(event)).await.is_err() || exiting {
+                break;
+            }
+        }
+        tracing::debug!("[{}] events observer done", sessionid);
+    });
+    Ok(sse::Sse::new(tokio_stream::wrappers::ReceiverStream::new(sse_rx)).keep_alive(sse::KeepAlive::default()))
+}
+
+#[derive(Deserialize)]
+struct CiphertextsQuery {
+    stream: Option<String>,
+    index: Option<usize>,
+    // download the encrypted sum instead of the stored ciphertexts
+    sum: Option<bool>,
+}
+
+// the part of a session's response message carrying downloaded ciphertexts
+#[derive(Deserialize)]
+struct CiphertextsMessage {
+    status: bool,
+    ciphertexts_binary: Option<String>,
+}
+
+// Download ciphertexts as a bincode Vec<LWE>, errors are answered in json
+async fn download_ciphertexts(
+    extract::Path(sessionid): extract::Path<String>,
+    query: Option<extract::Query<CiphertextsQuery>>,
+    extract::Extension(state): extract::Extension<SharedState>,
+) -> impl IntoResponse {
+    tracing::debug!("[{}] download_ciphertexts request received", sessionid);
+    let query = query.map(|extract::Query(query)| query).unwrap_or(CiphertextsQuery { stream: None, index: None, sum: None });
+    let action = if query.sum.unwrap_or(false) { "download_sum" } else { "download_ciphertexts" };
+    let message = serde_json::json!({
+        "action": action,
+        "stream": query.stream,
+        "index": query.index,
+        "encoding": "binary",
+    }).to_string();
+    let mut outcome = execute_session_action(&state, &sessionid, message).await;
+    if outcome.status_code == StatusCode::OK && outcome.response.status {
+        let ciphertexts = serde_json::from_str::<CiphertextsMessage>(&outcome.response.message)
+            .ok()
+            .filter(|message| message.status)
+            .and_then(|message| message.ciphertexts_binary)
+            .and_then(|encoded| base64::decode(encoded).ok());
+        match ciphertexts {
+            Some(bytes) => {
+                let mut response = Response::new(<<Json<SessionResponse> as IntoResponse>::Body>::from(bytes));
+                response.headers_mut().insert(
+                    HeaderName::from_static("content-type"),
+                    HeaderValue::from_static("application/octet-stream"),
+                );
+                return response;
+            }
+            None => {
+                outcome.response.status = false;
+                outcome.status_code = StatusCode::BAD_REQUEST;
+            }
+        }
+    }
+    session_action_response(outcome, WireFormat::Json)
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    stream: Option<String>,
+}
+
+// Upload ciphertexts sent as a bincode Vec<LWE> body
+async fn upload_ciphertexts(
+    extract::Path(sessionid): extract::Path<String>,
+    query: Option<extract::Query<UploadQuery>>,
+    body: bytes::Bytes,
+    extract::Extension(state): extract::Extension<SharedState>,
+) -> impl IntoResponse {
+    tracing::debug!("[{}] upload_ciphertexts request received, {} bytes", sessionid, body.len());
+    let stream = query.and_then(|extract::Query(query)| query.stream);
+    let message = serde_json::json!({
+        "action": "upload_ciphertexts",
+        "stream": stream,
+        "ciphertexts_binary": base64::encode(&body),
+    }).to_string();
+    session_action_response(execute_session_action(&state, &sessionid, message).await, WireFormat::Json)
+}
+
+async fn spawn_session(
+    kind: SessionKind,
+    sessionid: String,
+    init_message: String,
+    request_channel_rx: ReceiverSessionRequestChannel,
+    init_success_tx: SenderSessionResponseChannel,
+    session_config: SessionConfig,
+) -> JoinHandle<Result<(), ()>> {
+    match kind {
+        SessionKind::Open => session::spawn(sessionid, init_message, request_channel_rx, init_success_tx, session_config).await,
+        SessionKind::Encrypted => session_encrypted::spawn(sessionid, init_message, request_channel_rx, init_success_tx, session_config).await,
+    }
+}
+
+// Spawn a replacement for a crashed session under the same id, restoring its snapshot
+async fn restart_session(
+    state: &SharedState,
+    sessionid: &str,
+    instance: u64,
+    kind: SessionKind,
+    init_message: &str,
+    session_config: &SessionConfig,
+) -> Result<JoinHandle<Result<(), ()>>, String> {
+    let command_policy = state.read().unwrap().command_policy;
+    let (request_channel_tx, request_channel_rx) =
+        tokio::sync::mpsc::channel::<(SessionRequestCommand, SenderSessionResponseChannel)>(command_policy.queue_size);
+    let (init_success_tx, init_success_rx) = tokio::sync::oneshot::channel::<(SessionResponseStatus, String)>();
+    let join_handle = spawn_session(
+        kind,
+        sessionid.to_string(),
+        init_message.to_string(),
+        request_channel_rx,
+        init_success_tx,
+        session_config.clone(),
+    ).await;
+    wait_for_init(sessionid, init_success_rx).await?;
+
+    let mut shared_state = state.write().unwrap();
+    match shared_state.db.get_mut(sessionid) {
+        Some(handle) if handle.instance == instance => {
+            handle.request_channel_tx = request_channel_tx;
+            Ok(join_handle)
+        }
+        _ => Err(String::from("Session was removed while restarting")),
+    }
+}
+
+// Watch a session task: clean up once it exits, and record (or restart) it if it panics
+async fn supervise_session(
+    state: SharedState,
+    sessionid: String,
+    instance: u64,
+    kind: SessionKind,
+    init_message: String,
+    session_config: SessionConfig,
+    mut join_handle: JoinHandle<Result<(), ()>>,
+) {
+    let mut restarts: usize = 0;
+    let lifecycle = state.read().unwrap().lifecycle.clone();
+    loop {
+        let join_error = match join_handle.await {
+            Ok(_) => {
+                // the loop may have ended without an exit response, make sure it is deregistered
+                let reaped = state.write().unwrap().remove_session_instance(&sessionid, instance);
+                lifecycle.session(LifecycleEventKind::Exited, &sessionid, kind, "Session loop ended");
+                if reaped {
+                    tracing::info!("[{}] Removing session", sessionid);
+                    lifecycle.session(LifecycleEventKind::Reaped, &sessionid, kind, "Session loop ended without an exit action");
+                }
+                return;
+            }
+            Err(join_error) => join_error,
+        };
+        let panicked = join_error.is_panic();
+        let mut failure = supervisor::task_failure_message(join_error);
+        tracing::error!("[{}] {}", sessionid, failure);
+        lifecycle.session(LifecycleEventKind::Crashed, &sessionid, kind, &failure);
+
+        let supervisor_policy = {
+            let mut shared_state = state.write().unwrap();
+            if panicked {
+                shared_state.session_panics += 1;
+            }
+            shared_state.supervisor_policy
+        };
+        if panicked && supervisor_policy.restart_on_panic && restarts < supervisor_policy.max_restarts {
+            restarts += 1;
+            tracing::warn!("[{}] Restarting session from snapshot, restart {} of {}", sessionid, restarts, supervisor_policy.max_restarts);
+            match restart_session(&state, &sessionid, instance, kind, &init_message, &session_config).await {
+                Ok(new_join_handle) => {
+                    lifecycle.session(
+                        LifecycleEventKind::Restarted, &sessionid, kind,
+                        &format!("Restart {} of {}", restarts, supervisor_policy.max_restarts),
+                    );
+                    join_handle = new_join_handle;
+                    continue;
+                }
+                Err(err_msg) => {
+                    failure = format!("{}. Restart failed. {}", failure, err_msg);
+                    tracing::warn!("[{}] {}", sessionid, failure);
+                }
+            }
+        }
+
+        let mut shared_state = state.write().unwrap();
+        if shared_state.remove_session_instance(&sessionid, instance) {
+            tracing::info!("[{}] Removing failed session", sessionid);
+            lifecycle.session(LifecycleEventKind::Reaped, &sessionid, kind, &failure);
+            let max_failed_sessions = shared_state.supervisor_policy.max_failed_sessions;
+            shared_state.failed.record(sessionid, failure, max_failed_sessions);
+        }
+        return;
+    }
 }
 
 async fn shutdown_handler(
@@ -255,13 +1102,14 @@ async fn shutdown_handler(
     tracing::warn!("Server Shutdown request received");
     {
         let shared_state = state.read().unwrap();
-        for (sessionid, request_channel_tx) in &shared_state.db {
+        for (sessionid, handle) in &shared_state.db {
             tracing::info!("[{}] sending stop command to session", sessionid);
             tokio::spawn(
                 session_common::send_command(
                     "", 
-                    request_channel_tx.clone(), 
-                    session_common::SessionRequestCommand::SessionStop
+                    handle.request_channel_tx.clone(), 
+                    session_common::SessionRequestCommand::SessionStop,
+                    shared_state.command_policy,
                 )
             );
         }
@@ -305,6 +1153,7 @@ async fn main() {
     tracing_subscriber::fmt::init();
 
     let port = std::env::var("PORT").unwrap_or("8080".into()).parse::<u16>().unwrap();
+    let grpc_port: u16 = utils::get_env_or("GRPC_PORT", 50051);
 
     let localip = LOCALIP.clone();
     /*
@@ -322,10 +1171,55 @@ async fn main() {
     let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel::<()>(1);
     //let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
     tracing::warn!("Starting at localip {}, version {}", localip, version);
-    let shared_state = State {
+    let limits = CapacityLimits::from_env();
+    tracing::warn!("Capacity limits: {:?}", limits);
+    let memory_budget = MemoryBudget::new(limits.memory_budget_bytes);
+    let crypto_pool = CryptoPool::new(utils::get_env_or("CRYPTO_WORKERS", 2));
+    let command_policy = CommandPolicy::from_env();
+    tracing::warn!("Command policy: {:?}", command_policy);
+    let supervisor_policy = SupervisorPolicy::from_env();
+    tracing::warn!("Supervisor policy: {:?}", supervisor_policy);
+    let rolling = RollingConfig::from_env();
+    tracing::warn!("Rolling aggregates: {:?}", rolling);
+    let min_security_bits = utils::get_env_or("MIN_SECURITY_BITS", 80);
+    // secret key export stays disabled unless a token is configured
+    let key_export_token = std::env::var("KEY_EXPORT_TOKEN").ok().filter(|token| !token.is_empty());
+    let lifecycle = LifecycleBus::new(localip.clone());
+    let webhook_handle = webhooks::spawn_notifier(&lifecycle, webhooks::WebhookConfig::from_env());
+    let shared_state: SharedState = Arc::new(RwLock::new(State {
         db: HashMap::new(),
         shutdown_tx,
-    };
+        limits,
+        reserved: HashMap::new(),
+        memory_budget,
+        crypto_pool,
+        command_policy,
+        supervisor_policy,
+        rolling,
+        min_security_bits,
+        key_export_token,
+        failed: FailedSessions::default(),
+        session_panics: 0,
+        next_instance: 0,
+        lifecycle: lifecycle.clone(),
+        idempotency: IdempotencyPolicy::from_env(),
+        creating: HashSet::new(),
+    }));
+
+    // gRPC front end on its own port, stopped along with the http server
+    let (grpc_shutdown_tx, mut grpc_shutdown_rx) = tokio::sync::watch::channel(false);
+    let grpc_addr = SocketAddr::from(([0, 0, 0, 0], grpc_port));
+    tracing::warn!("gRPC listening on {}", grpc_addr);
+    let grpc_server = tonic::transport::Server::builder()
+        .add_service(grpc::SessionServiceServer::new(grpc::GrpcSessions::new(shared_state.clone())))
+        .serve_with_shutdown(grpc_addr, async move {
+            let _ = grpc_shutdown_rx.changed().await;
+        });
+    let grpc_handle = tokio::spawn(async move {
+        if let Err(e) = grpc_server.await {
+            tracing::error!("gRPC server failed. {}", e);
+        }
+    });
 
     // build our application with a route
     let app = Router::new()
@@ -333,15 +1227,20 @@ async fn main() {
         .route("/", get(root))
         .route("/debug", get(debug_handler_get))
         .route("/shutdown", get(shutdown_handler))
+        .route("/capacity", get(capacity_handler))
+        .route("/metrics", get(metrics_handler))
         .route("/sessions", get(list_sessions).post(create_session))
-        .route("/sessions/:sid", post(session_action))
+        .route("/sessions/:sid", get(session_info).post(session_action))
+        .route("/sessions/:sid/events", get(events_handler))
+        .route("/sessions/:sid/ciphertexts", get(download_ciphertexts).post(upload_ciphertexts))
+        .route("/sessions/:sid/fork", post(fork_session))
         .layer(
             ServiceBuilder::new()
                 .load_shed()
                 .concurrency_limit(1024)
                 .timeout(Duration::from_secs(10))
                 .layer(TraceLayer::new_for_http())
-                .layer(AddExtensionLayer::new(Arc::new(RwLock::new(shared_state))))
+                .layer(AddExtensionLayer::new(shared_state))
                 .into_inner(),
         )
         // Handle errors from middleware
@@ -357,17 +1256,24 @@ async fn main() {
             let mut signal_sigint = signal(SignalKind::interrupt()).unwrap();
             let mut signal_sigterm = signal(SignalKind::terminate()).unwrap();
             let mut signal_sigquit = signal(SignalKind::quit()).unwrap();
-            tokio::select! {
-                _ = shutdown_rx.recv() => {}
-                _ = signal_sigint.recv() => {tracing::warn!("SIGINT received");}
-                _ = signal_sigterm.recv() => {tracing::warn!("SIGTERM received");}
-                _ = signal_sigquit.recv() => {tracing::warn!("SIGQUIT received");}
-            }
+            let reason = tokio::select! {
+                _ = shutdown_rx.recv() => "Shutdown request",
+                _ = signal_sigint.recv() => {tracing::warn!("SIGINT received"); "SIGINT"}
+                _ = signal_sigterm.recv() => {tracing::warn!("SIGTERM received"); "SIGTERM"}
+                _ = signal_sigquit.recv() => {tracing::warn!("SIGQUIT received"); "SIGQUIT"}
+            };
+            lifecycle.publish(LifecycleEventKind::ServerShutdown, None, None, reason);
             //shutdown_rx.recv().await;
+            let _ = grpc_shutdown_tx.send(true);
             tracing::warn!("Server will finish in two seconds");
             tokio::time::sleep(Duration::from_millis(1000)).await;
         })
         .await
         .unwrap();
+    let _ = grpc_handle.await;
+    if let Some(webhook_handle) = webhook_handle {
+        // deliveries still retrying are dropped with the runtime
+        webhook_handle.abort();
+    }
     tracing::warn!("Server finished");
 }
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Global budget for the approximate memory held by all sessions
+#[derive(Debug)]
+pub struct MemoryBudget {
+    budget_bytes: usize,
+    used_bytes: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(budget_bytes: usize) -> Arc<Self> {
+        Arc::new(Self {
+            budget_bytes,
+            used_bytes: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn budget_bytes(&self) -> usize {
+        self.budget_bytes
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct SessionMemoryInner {
+    budget: Arc<MemoryBudget>,
+    held_bytes: AtomicUsize,
+}
+
+/// Memory held by one session. Shared between the session task and the server;
+/// whatever is still held is returned to the budget once both have dropped it.
+#[derive(Debug, Clone)]
+pub struct SessionMemory(Arc<SessionMemoryInner>);
+
+impl SessionMemory {
+    pub fn new(budget: Arc<MemoryBudget>) -> Self {
+        Self(Arc::new(SessionMemoryInner {
+            budget,
+            held_bytes: AtomicUsize::new(0),
+        }))
+    }
+
+    pub fn held_bytes(&self) -> usize {
+        self.0.held_bytes.load(Ordering::Relaxed)
+    }
+
+    /// record the session's current footprint, adjusting the global usage by the difference
+    pub fn set(&self, bytes: usize) {
+        let previous = self.0.held_bytes.swap(bytes, Ordering::Relaxed);
+        if bytes > previous {
+            self.0.budget.used_bytes.fetch_add(bytes - previous, Ordering::Relaxed);
+        } else {
+            self.0.budget.used_bytes.fetch_sub(previous - bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// true if the global budget leaves room for `additional` more bytes
+    pub fn can_grow(&self, additional: usize) -> bool {
+        self.0.budget.used_bytes() + additional <= self.0.budget.budget_bytes()
+    }
+}
+
+impl Drop for SessionMemoryInner {
+    fn drop(&mut self) {
+        let held = *self.held_bytes.get_mut();
+        self.budget.used_bytes.fetch_sub(held, Ordering::Relaxed);
+    }
+}
+
+/// approximate bytes held by a vector of plain values
+pub fn plain_values_bytes(count: usize) -> usize {
+    count * std::mem::size_of::<f64>()
+}
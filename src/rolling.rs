@@ -0,0 +1,352 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crate::session_common::SessionRequestMessage;
+use crate::utils::get_env_or;
+
+/// How a stream's rolling aggregates are maintained. The window keeps at most `window_size`
+/// values, and only those younger than `window_secs` when set. `half_life_secs` enables an
+/// exponentially decaying mean.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RollingConfig {
+    pub window_size: usize,
+    pub window_secs: Option<f64>,
+    pub half_life_secs: Option<f64>,
+}
+
+// zero disables the optional settings
+fn positive(value: f64) -> Option<f64> {
+    if value > 0. { Some(value) } else { None }
+}
+
+impl RollingConfig {
+    pub fn from_env() -> Self {
+        Self {
+            window_size: get_env_or("ROLLING_WINDOW_SIZE", 100),
+            window_secs: positive(get_env_or("ROLLING_WINDOW_SECS", 0.)),
+            half_life_secs: positive(get_env_or("ROLLING_HALF_LIFE_SECS", 0.)),
+        }
+    }
+
+    /// the session defaults, overridden by the settings given with a request
+    pub fn with_overrides(&self, request_message: &SessionRequestMessage, max_values: usize) -> Result<Self, String> {
+        let mut config = *self;
+        if let Some(window_size) = request_message.window_size {
+            config.window_size = window_size;
+        }
+        if let Some(window_secs) = request_message.window_secs {
+            if window_secs < 0. || window_secs.is_nan() {
+                return Err(String::from("window_secs must not be negative"));
+            }
+            config.window_secs = positive(window_secs);
+        }
+        if let Some(half_life_secs) = request_message.half_life_secs {
+            if half_life_secs < 0. || half_life_secs.is_nan() {
+                return Err(String::from("half_life_secs must not be negative"));
+            }
+            config.half_life_secs = positive(half_life_secs);
+        }
+        if config.window_size == 0 || config.window_size > max_values {
+            return Err(format!("window_size must be between 1 and {}", max_values));
+        }
+        Ok(config)
+    }
+}
+
+/// weight of a value of the given age in a mean decaying with `half_life_secs`
+pub fn decay_weight(age: Duration, half_life_secs: f64) -> f64 {
+    0.5f64.powf(age.as_secs_f64() / half_life_secs)
+}
+
+/// The most recent items of a stream, bounded by count and optionally by age
+#[derive(Debug, Clone)]
+pub struct RollingWindow<T> {
+    config: RollingConfig,
+    items: VecDeque<(Instant, T)>,
+}
+
+impl<T> RollingWindow<T> {
+    pub fn new(config: RollingConfig) -> Self {
+        Self { config, items: VecDeque::new() }
+    }
+
+    pub fn config(&self) -> &RollingConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// add an item, returning the items that fell out of the window
+    pub fn push(&mut self, now: Instant, item: T) -> Vec<T> {
+        self.items.push_back((now, item));
+        self.evict(now)
+    }
+
+    /// drop items beyond the window size or older than the window duration
+    pub fn evict(&mut self, now: Instant) -> Vec<T> {
+        let mut evicted = Vec::new();
+        while self.items.len() > self.config.window_size {
+            if let Some((_, item)) = self.items.pop_front() {
+                evicted.push(item);
+            }
+        }
+        if let Some(window_secs) = self.config.window_secs {
+            while let Some((added, _)) = self.items.front() {
+                if now.saturating_duration_since(*added).as_secs_f64() <= window_secs {
+                    break;
+                }
+                if let Some((_, item)) = self.items.pop_front() {
+                    evicted.push(item);
+                }
+            }
+        }
+        evicted
+    }
+
+    /// a window with the same timestamps over items mapped by `f`
+    pub fn try_map<U, E, F>(&self, mut f: F) -> Result<RollingWindow<U>, E>
+    where
+        F: FnMut(&T) -> Result<U, E>,
+    {
+        let mut items = VecDeque::with_capacity(self.items.len());
+        for (added, item) in &self.items {
+            items.push_back((*added, f(item)?));
+        }
+        Ok(RollingWindow { config: self.config, items })
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &T> {
+        self.items.iter().map(|(_, item)| item)
+    }
+
+    /// decay weight of each item, oldest first, when a half life is configured
+    pub fn decay_weights(&self, now: Instant) -> Option<Vec<f64>> {
+        let half_life_secs = self.config.half_life_secs?;
+        Some(self.items.iter().map(|(added, _)| decay_weight(now.saturating_duration_since(*added), half_life_secs)).collect())
+    }
+}
+
+/// Rolling statistics of a stream, as returned by the rolling action
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RollingStats {
+    pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sum: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variance: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decayed_mean: Option<f64>,
+    // sum of the decay weights, the effective number of values behind decayed_mean
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decayed_weight: Option<f64>,
+    pub window_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub half_life_secs: Option<f64>,
+}
+
+impl RollingStats {
+    pub fn empty(config: &RollingConfig) -> Self {
+        Self {
+            window_size: config.window_size,
+            window_secs: config.window_secs,
+            half_life_secs: config.half_life_secs,
+            ..Self::default()
+        }
+    }
+}
+
+// exponentially decaying sums, kept up to date as of `updated`
+#[derive(Debug, Clone)]
+struct Decayed {
+    updated: Instant,
+    weighted_sum: f64,
+    weight: f64,
+}
+
+/// Sliding window and decaying aggregates over plain values, updated as values arrive
+#[derive(Debug, Clone)]
+pub struct RollingAggregates {
+    window: RollingWindow<f64>,
+    sum: f64,
+    sum_of_squares: f64,
+    decayed: Option<Decayed>,
+}
+
+impl RollingAggregates {
+    pub fn new(config: RollingConfig) -> Self {
+        Self { window: RollingWindow::new(config), sum: 0., sum_of_squares: 0., decayed: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
+    pub fn push(&mut self, now: Instant, value: f64) {
+        self.sum += value;
+        self.sum_of_squares += value * value;
+        let evicted = self.window.push(now, value);
+        self.remove(&evicted);
+
+        // the decaying mean covers every value seen, not only the window
+        if let Some(half_life_secs) = self.window.config().half_life_secs {
+            let decayed = self.decayed.get_or_insert(Decayed { updated: now, weighted_sum: 0., weight: 0. });
+            let factor = decay_weight(now.saturating_duration_since(decayed.updated), half_life_secs);
+            decayed.weighted_sum = decayed.weighted_sum * factor + value;
+            decayed.weight = decayed.weight * factor + 1.;
+            decayed.updated = now;
+        }
+    }
+
+    fn remove(&mut self, evicted: &[f64]) {
+        for value in evicted {
+            self.sum -= value;
+            self.sum_of_squares -= value * value;
+        }
+        // start over from exact zero so rounding errors do not build up
+        if self.window.len() == 0 {
+            self.sum = 0.;
+            self.sum_of_squares = 0.;
+        }
+    }
+
+    pub fn stats(&mut self, now: Instant) -> RollingStats {
+        let evicted = self.window.evict(now);
+        self.remove(&evicted);
+
+        let mut stats = RollingStats::empty(self.window.config());
+        let count = self.window.len();
+        stats.count = count;
+        if count > 0 {
+            let mean = self.sum / (count as f64);
+            stats.mean = Some(mean);
+            stats.sum = Some(self.sum);
+            stats.min = self.window.items().cloned().fold(None, |acc, v| Some(acc.map_or(v, |m: f64| m.min(v))));
+            stats.max = self.window.items().cloned().fold(None, |acc, v| Some(acc.map_or(v, |m: f64| m.max(v))));
+            stats.variance = Some((self.sum_of_squares / (count as f64) - mean * mean).max(0.));
+        }
+        if let (Some(decayed), Some(half_life_secs)) = (&self.decayed, self.window.config().half_life_secs) {
+            let factor = decay_weight(now.saturating_duration_since(decayed.updated), half_life_secs);
+            if decayed.weight > 0. {
+                stats.decayed_mean = Some(decayed.weighted_sum / decayed.weight);
+                stats.decayed_weight = Some(decayed.weight * factor);
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(window_size: usize, window_secs: Option<f64>, half_life_secs: Option<f64>) -> RollingConfig {
+        RollingConfig { window_size, window_secs, half_life_secs }
+    }
+
+    fn after(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn window_evicts_the_oldest_beyond_its_size() {
+        let start = Instant::now();
+        let mut window = RollingWindow::new(config(2, None, None));
+        assert!(window.push(start, 1).is_empty());
+        assert!(window.push(start, 2).is_empty());
+        assert_eq!(window.push(start, 3), vec![1]);
+        assert_eq!(window.items().cloned().collect::<Vec<i32>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn window_evicts_items_older_than_its_duration() {
+        let start = Instant::now();
+        let mut window = RollingWindow::new(config(10, Some(5.), None));
+        window.push(start, 1);
+        window.push(after(start, 3), 2);
+        // exactly as old as the window still counts
+        assert!(window.evict(after(start, 5)).is_empty());
+        assert_eq!(window.evict(after(start, 6)), vec![1]);
+        assert_eq!(window.evict(after(start, 20)), vec![2]);
+        assert_eq!(window.len(), 0);
+    }
+
+    #[test]
+    fn decay_halves_weights_every_half_life() {
+        assert_eq!(decay_weight(Duration::from_secs(0), 10.), 1.);
+        assert_eq!(decay_weight(Duration::from_secs(10), 10.), 0.5);
+        assert_eq!(decay_weight(Duration::from_secs(20), 10.), 0.25);
+
+        let start = Instant::now();
+        let mut window = RollingWindow::new(config(10, None, Some(10.)));
+        window.push(start, 1);
+        window.push(after(start, 10), 2);
+        assert_eq!(window.decay_weights(after(start, 10)), Some(vec![0.5, 1.]));
+        assert_eq!(RollingWindow::<i32>::new(config(10, None, None)).decay_weights(start), None);
+    }
+
+    #[test]
+    fn aggregates_drop_evicted_values() {
+        let start = Instant::now();
+        let mut aggregates = RollingAggregates::new(config(2, None, None));
+        for value in [1., 2., 6.].iter() {
+            aggregates.push(start, *value);
+        }
+        let stats = aggregates.stats(start);
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.sum, Some(8.));
+        assert_eq!(stats.mean, Some(4.));
+        assert_eq!((stats.min, stats.max), (Some(2.), Some(6.)));
+        assert_eq!(stats.variance, Some(4.));
+    }
+
+    #[test]
+    fn aggregates_of_an_expired_window_are_empty() {
+        let start = Instant::now();
+        let mut aggregates = RollingAggregates::new(config(10, Some(1.), None));
+        aggregates.push(start, 3.);
+        let stats = aggregates.stats(after(start, 2));
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.mean, None);
+        assert_eq!(stats.sum, None);
+    }
+
+    #[test]
+    fn decayed_mean_covers_values_beyond_the_window() {
+        let start = Instant::now();
+        let mut aggregates = RollingAggregates::new(config(1, None, Some(10.)));
+        aggregates.push(start, 4.);
+        aggregates.push(after(start, 10), 1.);
+        // (4 * 0.5 + 1) / (0.5 + 1)
+        let stats = aggregates.stats(after(start, 10));
+        assert_eq!(stats.mean, Some(1.));
+        assert_eq!(stats.decayed_mean, Some(2.));
+        assert_eq!(stats.decayed_weight, Some(1.5));
+        // the weight keeps decaying between values
+        assert_eq!(aggregates.stats(after(start, 20)).decayed_weight, Some(0.75));
+    }
+
+    #[test]
+    fn overrides_are_validated() {
+        let defaults = config(100, None, None);
+        let request = |json: &str| serde_json::from_str::<SessionRequestMessage>(json).unwrap();
+        let overridden = defaults.with_overrides(&request(r#"{"action": "create_stream", "window_size": 5, "window_secs": 0, "half_life_secs": 30}"#), 1000).unwrap();
+        assert_eq!(overridden.window_size, 5);
+        // zero disables the window duration
+        assert_eq!(overridden.window_secs, None);
+        assert_eq!(overridden.half_life_secs, Some(30.));
+        assert!(defaults.with_overrides(&request(r#"{"action": "create_stream", "window_size": 0}"#), 1000).is_err());
+        assert!(defaults.with_overrides(&request(r#"{"action": "create_stream", "window_size": 1001}"#), 1000).is_err());
+        assert!(defaults.with_overrides(&request(r#"{"action": "create_stream", "window_secs": -1}"#), 1000).is_err());
+        assert!(defaults.with_overrides(&request(r#"{"action": "create_stream", "half_life_secs": -1}"#), 1000).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::session_common::{send_response, SenderSessionResponseChannel, SessionResponseMessage, SessionResponseStatus};
+
+/// Client supplied sequence numbers of a session's actions. Sequenced actions must arrive
+/// as 1, 2, 3, ... and each is applied once, whatever its outcome. Actions without a sequence
+/// number are not ordered.
+#[derive(Debug, Default)]
+pub struct ActionSequence {
+    last_applied: Option<u64>,
+}
+
+impl ActionSequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn expected(&self) -> u64 {
+        self.last_applied.map_or(1, |last_applied| last_applied + 1)
+    }
+
+    /// Accept the next action, rejecting repeated, out of order and gapped sequence numbers
+    pub fn admit(&mut self, seq: Option<u64>) -> Result<(), String> {
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return Ok(()),
+        };
+        let expected = self.expected();
+        if seq < expected {
+            return Err(format!("Sequence {} already applied, expected {}", seq, expected));
+        }
+        if seq > expected {
+            // an earlier action is still in flight or was lost, the client resends from the expected one
+            return Err(format!("Sequence {} is ahead, expected {}", seq, expected));
+        }
+        self.last_applied = Some(seq);
+        Ok(())
+    }
+
+    /// send a response reporting the last applied sequence number
+    pub fn respond(
+        &self,
+        sessionid: &String,
+        response_status: SessionResponseStatus,
+        mut response_message: SessionResponseMessage,
+        response_tx: SenderSessionResponseChannel,
+    ) -> bool {
+        response_message.last_seq = self.last_applied;
+        send_response(sessionid, response_status, response_message, response_tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn sequenced_actions_apply_in_order() {
+        let mut sequence = ActionSequence::new();
+        assert_eq!(sequence.admit(Some(1)), Ok(()));
+        assert_eq!(sequence.admit(Some(2)), Ok(()));
+        // unsequenced actions neither need nor move the sequence
+        assert_eq!(sequence.admit(None), Ok(()));
+        assert_eq!(sequence.admit(Some(3)), Ok(()));
+    }
+
+    #[test]
+    fn sequences_must_start_at_1() {
+        assert!(ActionSequence::new().admit(Some(0)).is_err());
+        assert!(ActionSequence::new().admit(Some(2)).is_err());
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut sequence = ActionSequence::new();
+        sequence.admit(Some(1)).unwrap();
+        sequence.admit(Some(2)).unwrap();
+        assert_eq!(sequence.admit(Some(2)), Err(String::from("Sequence 2 already applied, expected 3")));
+        assert!(sequence.admit(Some(1)).is_err());
+        assert_eq!(sequence.admit(Some(3)), Ok(()));
+    }
+
+    #[test]
+    fn gaps_are_rejected_until_the_expected_action_arrives() {
+        let mut sequence = ActionSequence::new();
+        sequence.admit(Some(1)).unwrap();
+        assert_eq!(sequence.admit(Some(3)), Err(String::from("Sequence 3 is ahead, expected 2")));
+        // the rejected action is not applied, so it is still ahead
+        assert!(sequence.admit(Some(3)).is_err());
+        assert_eq!(sequence.admit(Some(2)), Ok(()));
+        assert_eq!(sequence.admit(Some(3)), Ok(()));
+    }
+
+    #[test]
+    fn responses_report_the_last_applied_sequence() {
+        let respond = |sequence: &ActionSequence| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            assert!(sequence.respond(&String::from("test"), SessionResponseStatus::SessionOk, SessionResponseMessage::default(), response_tx));
+            let (_, message) = response_rx.try_recv().unwrap();
+            serde_json::from_str::<serde_json::Value>(&message).unwrap()["last_seq"].clone()
+        };
+        let mut sequence = ActionSequence::new();
+        assert_eq!(respond(&sequence), serde_json::Value::Null);
+        sequence.admit(Some(1)).unwrap();
+        assert!(sequence.admit(Some(5)).is_err());
+        assert_eq!(respond(&sequence), serde_json::json!(1));
+    }
+}
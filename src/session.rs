@@ -1,24 +1,145 @@
 use crate::session_common::*;
+use tokio::task::JoinHandle;
+use crate::memory::plain_values_bytes;
+use crate::statistics;
+use crate::rolling::{RollingAggregates, RollingStats};
+use crate::idempotency::IdempotencyCache;
+use crate::sequence::ActionSequence;
+use crate::checkpoint::Checkpoints;
+use crate::group::{Group, GroupConfig};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::time::Instant;
+
+// number of values held across all streams
+fn stored_values(streams: &HashMap<String, Vec<f64>>) -> usize {
+    streams.values().map(Vec::len).sum()
+}
+
+// number of values, or of vectors for vector actions, one stream holds
+fn held_by_stream(streams: &HashMap<String, Vec<f64>>, vectors: &HashMap<String, Vec<Vec<f64>>>, action: &str, stream_name: &str) -> usize {
+    if action == "encrypt_vector" {
+        vectors.get(stream_name).map_or(0, Vec::len)
+    } else {
+        streams.get(stream_name).map_or(0, Vec::len)
+    }
+}
+
+// number of vector slots held across all streams, each slot counts as a value
+fn vector_slots(vectors: &HashMap<String, Vec<Vec<f64>>>) -> usize {
+    vectors.values().flatten().map(Vec::len).sum()
+}
+
+// number of values held in rolling windows across all streams
+fn windowed_values(rolling: &HashMap<String, RollingAggregates>) -> usize {
+    rolling.values().map(RollingAggregates::len).sum()
+}
+
+// approximate bytes held by the values, vectors and rolling windows of the streams
+fn held_bytes(
+    streams: &HashMap<String, Vec<f64>>,
+    vectors: &HashMap<String, Vec<Vec<f64>>>,
+    rolling: &HashMap<String, RollingAggregates>,
+) -> usize {
+    plain_values_bytes(stored_values(streams) + vector_slots(vectors) + windowed_values(rolling))
+}
+
+// Stored values saved by the checkpoint action, and copied to forked sessions
+#[derive(Clone)]
+pub struct Checkpoint {
+    streams: HashMap<String, Vec<f64>>,
+    vectors: HashMap<String, Vec<Vec<f64>>>,
+    rolling: HashMap<String, RollingAggregates>,
+}
+
+// update memory accounting and the restart snapshot after the stored values change
+fn values_changed(
+    session_config: &SessionConfig,
+    streams: &HashMap<String, Vec<f64>>,
+    vectors: &HashMap<String, Vec<Vec<f64>>>,
+    rolling: &HashMap<String, RollingAggregates>,
+    checkpoints: &Checkpoints<Checkpoint>,
+) {
+    session_config.memory.set(held_bytes(streams, vectors, rolling) + checkpoints.held_bytes());
+    publish_snapshot(&session_config.snapshot, streams, vectors);
+}
 
 pub async fn spawn(
     sessionid: String, init_message: String,
     request_channel_rx: ReceiverSessionRequestChannel,
     init_success_tx: SenderSessionResponseChannel,
-) -> () {
+    session_config: SessionConfig,
+) -> JoinHandle<Result<(), ()>> {
     tracing::info!("[{}] Spawning session", sessionid);
     // launch the session loop as a tokio task
-    tokio::spawn(session_loop(sessionid.clone(), request_channel_rx, init_message, init_success_tx));
+    tokio::spawn(session_loop(sessionid.clone(), request_channel_rx, init_message, init_success_tx, session_config))
 }
 
 async fn session_loop(
     sessionid: String, 
     mut request_channel_rx: ReceiverSessionRequestChannel,
-    _init_message: String,
+    init_message: String,
     init_success_tx: SenderSessionResponseChannel,
+    session_config: SessionConfig,
 ) -> Result<(), ()> {
     tracing::info!("[{}] Starting session loop", sessionid);
 
-    let mut values: Vec<f64> = Vec::new();
+    // a session shared by a group checks every action against the participant sending it
+    let mut group = match GroupConfig::parse(init_message.as_str()) {
+        Ok(config) => config.map(|config| Group::new(&sessionid, config)),
+        Err(e) => {
+            let status_message = format!("[{}] Session initialized failed. {}", sessionid, e);
+            tracing::warn!("{}", status_message);
+            send_response(
+                &sessionid,
+                SessionResponseStatus::SessionExit,
+                SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                init_success_tx
+            );
+            return Err(());
+        }
+    };
+    let hide_contributions = matches!(&group, Some(group) if group.hides_contributions());
+
+    let mut streams: HashMap<String, Vec<f64>> = HashMap::new();
+    // rolling aggregates of each stream, kept apart from the values so mean does not reset them
+    let mut rolling: HashMap<String, RollingAggregates> = HashMap::new();
+    // vector submissions of each stream, aggregated slot by slot
+    let mut vectors: HashMap<String, Vec<Vec<f64>>> = HashMap::new();
+    let mut idempotency = IdempotencyCache::new(session_config.idempotency);
+    let mut sequence = ActionSequence::new();
+    // checkpoints are not part of the restart snapshot
+    let mut checkpoints: Checkpoints<Checkpoint> = Checkpoints::new(session_config.max_checkpoints);
+    if let Some(SessionSeed::Open(seed)) = take_seed(&session_config.seed) {
+        let seed_bytes = held_bytes(&seed.streams, &seed.vectors, &seed.rolling);
+        if !session_config.memory.can_grow(seed_bytes) {
+            let status_message = format!("[{}] Session initialized failed. Server memory budget exhausted, forked values need {} bytes", sessionid, seed_bytes);
+            tracing::warn!("{}", status_message);
+            send_response(
+                &sessionid,
+                SessionResponseStatus::SessionExit,
+                SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                init_success_tx
+            );
+            return Err(());
+        }
+        streams = seed.streams;
+        vectors = seed.vectors;
+        rolling = seed.rolling;
+        tracing::info!("[{}] Starting from {} forked values in {} streams", sessionid, stored_values(&streams) + vector_slots(&vectors), streams.len());
+        values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+    } else if let Some(snapshot) = load_snapshot(&session_config.snapshot) {
+        if !snapshot.streams.is_empty() {
+            // rolling windows are not part of the snapshot and start empty
+            tracing::info!("[{}] Restoring {} streams from snapshot", sessionid, snapshot.streams.len());
+            streams = snapshot.streams;
+            vectors = snapshot.vectors;
+            for name in streams.keys() {
+                rolling.insert(name.clone(), RollingAggregates::new(session_config.rolling));
+            }
+            session_config.memory.set(plain_values_bytes(stored_values(&streams) + vector_slots(&vectors)));
+        }
+    }
 
     if !send_response(
         &sessionid,
@@ -36,24 +157,58 @@ async fn session_loop(
             SessionRequestCommand::SessionStop => {
                 let status_message = format!("[{}] Stopping session", sessionid);
                 tracing::info!("{}", status_message);
-                if !send_response(
+                if !sequence.respond(
                     &sessionid, 
                     SessionResponseStatus::SessionExit, 
                     SessionResponseMessage{status: true, status_message, ..SessionResponseMessage::default()}, 
                     resp) {
                     tracing::warn!("[{}] Error sending Response for SessionStop", sessionid);
                 }
+                session_config.events.exiting("stopped");
                 break;
             }
 
+            SessionRequestCommand::SessionFork(seed) => {
+                if resp.is_closed() {
+                    tracing::debug!("[{}] Caller went away, skipping SessionFork", sessionid);
+                    continue;
+                }
+                if group.is_some() {
+                    // the fork would hand out new participant tokens for the group's values
+                    let status_message = format!("[{}] Fork refused, group sessions can not be forked", sessionid);
+                    tracing::info!("{}", status_message);
+                    sequence.respond(
+                        &sessionid,
+                        SessionResponseStatus::SessionOk,
+                        SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                        resp
+                    );
+                    continue;
+                }
+                let checkpoint = Checkpoint { streams: streams.clone(), vectors: vectors.clone(), rolling: rolling.clone() };
+                *seed.lock().unwrap_or_else(|e| e.into_inner()) = Some(SessionSeed::Open(checkpoint));
+                let status_message = format!("[{}] Fork, {} values copied", sessionid, stored_values(&streams) + vector_slots(&vectors));
+                tracing::info!("{}", status_message);
+                sequence.respond(
+                    &sessionid,
+                    SessionResponseStatus::SessionOk,
+                    SessionResponseMessage{status: true, status_message, ..SessionResponseMessage::default()},
+                    resp
+                );
+            }
+
             SessionRequestCommand::SessionCommand(message) => {
+                if resp.is_closed() {
+                    tracing::debug!("[{}] Caller went away, skipping SessionCommand. Message: {}", sessionid, message);
+                    continue;
+                }
                 tracing::debug!("[{}] Received SessionCommand. Message: {}", sessionid, message);
                 let request_message: SessionRequestMessage = match serde_json::from_str(message.as_str()) {
                     Ok(m) => m,
                     Err(e) => {
                         let status_message = format!("[{}] Failed to json decode request's message field. {}", sessionid, e.to_string());
                         tracing::warn!("{}", status_message);
-                        send_response(
+                        sequence.respond(
                             &sessionid, 
                             SessionResponseStatus::SessionOk, 
                             SessionResponseMessage{status: false, status_message, ..SessionResponseMessage::default()},
@@ -64,53 +219,382 @@ async fn session_loop(
                 };
 
                 tracing::debug!("[{}] Received message: {:?}", sessionid, request_message);
-
+                let stream_name = request_message.stream_name().to_string();
+                // a replay skips admission, only the participant who sent the action can replay it
+                let resp = match idempotency.intercept(
+                    &sessionid,
+                    request_message.idempotency_key.as_deref(),
+                    request_message.participant_token.as_deref(),
+                    resp,
+                ) {
+                    Some(resp) => resp,
+                    None => continue,
+                };
+                let participant = match group.as_mut().map(|group| group.admit(&request_message)).transpose() {
+                    Ok(participant) => participant,
+                    Err(e) => {
+                        let err_str = format!("[{}] Action rejected. {}", sessionid, e);
+                        tracing::debug!("{}", err_str);
+                        let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                };
+                if let Err(e) = sequence.admit(request_message.seq) {
+                    let err_str = format!("[{}] Action rejected. {}", sessionid, e);
+                    tracing::debug!("{}", err_str);
+                    let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                    sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                    continue;
+                }
+                let held_before = held_by_stream(&streams, &vectors, &request_message.action, &stream_name);
 
                 match request_message.action.as_str() {
                     "encrypt" => {
-                        let msg_str = format!("[{}] Encrypt action received. Value {}", sessionid, request_message.value);
+                        let stored = stored_values(&streams) + vector_slots(&vectors);
+                        if stored >= session_config.max_values {
+                            let err_str = format!("[{}] Encrypt action rejected. Session already holds {} values", sessionid, stored);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        // the value is also added to the stream's rolling window
+                        if !session_config.memory.can_grow(plain_values_bytes(2)) {
+                            let err_str = format!("[{}] Encrypt action rejected. Server memory budget exhausted", sessionid);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        let msg_str = format!("[{}] Encrypt action received. Stream {}, Value {}", sessionid, stream_name, request_message.value);
                         tracing::debug!("{}", msg_str);
 
-                        values.push(request_message.value);
+                        // streams are created on first use
+                        rolling.entry(stream_name.clone())
+                            .or_insert_with(|| RollingAggregates::new(session_config.rolling))
+                            .push(Instant::now(), request_message.value);
+                        // observers of a group hiding contributions only see that a value arrived
+                        let event_value = if hide_contributions { None } else { Some(request_message.value) };
+                        session_config.events.value_received(&stream_name, event_value, None);
+                        streams.entry(stream_name).or_default().push(request_message.value);
+                        values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                        if let (Some(group), Some(participant)) = (group.as_mut(), participant.as_deref()) {
+                            group.contributed(participant, &request_message, 1, held_before);
+                        }
 
                         let response_message = SessionResponseMessage {
                             status: true,
                             status_message: msg_str,
                             value: request_message.value,
+                            ..SessionResponseMessage::default()
                         };
 
-                        send_response(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
                         continue;
                     }
                     "mean" => {
-                        let msg_str = format!("[{}] Mean action received", sessionid);
+                        let msg_str = format!("[{}] Mean action received. Stream {}", sessionid, stream_name);
                         tracing::debug!("{}", msg_str);
                         let mut response_message = SessionResponseMessage{status_message: msg_str, ..SessionResponseMessage::default()};
                         
-                        if values.len() > 0 {
+                        if let Some(values) = streams.get_mut(&stream_name) {
+                            if !values.is_empty() {
+                                response_message.status = true;
+                                let mut sum: f64 = 0.;
+                                for value in values.iter() {
+                                    sum += value;
+                                };
+                                response_message.value = sum / (values.len() as f64);
+                                // mean has always cleared the values, unless asked not to
+                                if request_message.consume.unwrap_or(true) {
+                                    values.clear();
+                                    values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                                }
+                            }
+                        }
+                        session_config.events.aggregate_computed(&stream_name, "mean", &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    action if statistics::is_statistics_action(action) => {
+                        let msg_str = format!("[{}] Statistics action {} received. Stream {}", sessionid, action, stream_name);
+                        tracing::debug!("{}", msg_str);
+                        let mut response_message = SessionResponseMessage{status_message: msg_str, ..SessionResponseMessage::default()};
+
+                        let values: &[f64] = match streams.get(&stream_name) {
+                            Some(values) => values,
+                            None => &[],
+                        };
+                        match statistics::compute(action, values, &request_message, &mut response_message) {
+                            Ok(()) => {
+                                response_message.status = true;
+                                // other statistics leave the values in place, unless asked to consume them
+                                if request_message.consume.unwrap_or(false) {
+                                    if let Some(values) = streams.get_mut(&stream_name) {
+                                        values.clear();
+                                    }
+                                    values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                                }
+                            }
+                            Err(e) => {
+                                let err_str = format!("[{}] Statistics action {} failed. {}", sessionid, action, e);
+                                tracing::debug!("{}", err_str);
+                                response_message.status_message = err_str;
+                            }
+                        }
+                        session_config.events.aggregate_computed(&stream_name, action, &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "encrypt_vector" => {
+                        let vector = request_message.vector.clone().unwrap_or_default();
+                        let stream_vector_len = vectors.get(&stream_name).and_then(|stream_vectors| stream_vectors.first()).map(Vec::len);
+                        if let Err(e) = check_vector(&vector, stream_vector_len) {
+                            let err_str = format!("[{}] Encrypt vector action rejected. {}", sessionid, e);
+                            tracing::debug!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                            continue;
+                        }
+                        let stored = stored_values(&streams) + vector_slots(&vectors);
+                        if stored + vector.len() > session_config.max_values {
+                            let err_str = format!("[{}] Encrypt vector action rejected. Session already holds {} values", sessionid, stored);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        if !session_config.memory.can_grow(plain_values_bytes(vector.len())) {
+                            let err_str = format!("[{}] Encrypt vector action rejected. Server memory budget exhausted", sessionid);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        let msg_str = format!("[{}] Encrypt vector action received. Stream {}, Length {}", sessionid, stream_name, vector.len());
+                        tracing::debug!("{}", msg_str);
+
+                        streams.entry(stream_name.clone()).or_default();
+                        session_config.events.value_received(&stream_name, None, Some(vector.len()));
+                        vectors.entry(stream_name).or_default().push(vector.clone());
+                        values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                        if let (Some(group), Some(participant)) = (group.as_mut(), participant.as_deref()) {
+                            group.contributed(participant, &request_message, vector.len(), held_before);
+                        }
+
+                        let response_message = SessionResponseMessage {
+                            status: true,
+                            status_message: msg_str,
+                            value: vector.len() as f64,
+                            values: Some(vector),
+                            ..SessionResponseMessage::default()
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "vector_mean" | "vector_sum" => {
+                        let action = request_message.action.as_str();
+                        let msg_str = format!("[{}] {} action received. Stream {}", sessionid, action, stream_name);
+                        tracing::debug!("{}", msg_str);
+                        let mut response_message = SessionResponseMessage{status_message: msg_str, ..SessionResponseMessage::default()};
+
+                        if let Some(stream_vectors) = vectors.get_mut(&stream_name).filter(|stream_vectors| !stream_vectors.is_empty()) {
                             response_message.status = true;
-                            let mut sum: f64 = 0.;
-                            for value in &values {
-                                sum += value;
+                            response_message.value = stream_vectors.len() as f64;
+                            response_message.values = if action == "vector_mean" {
+                                statistics::vector_mean(stream_vectors)
+                            } else {
+                                statistics::vector_sum(stream_vectors)
                             };
-                            response_message.value = sum / (values.len() as f64);
-                            values.clear();
+                            // like mean, vector_mean clears the vectors unless asked not to
+                            if request_message.consume.unwrap_or(action == "vector_mean") {
+                                stream_vectors.clear();
+                                values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                            }
+                        }
+                        session_config.events.aggregate_computed(&stream_name, action, &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "rolling" => {
+                        let msg_str = format!("[{}] Rolling action received. Stream {}", sessionid, stream_name);
+                        tracing::debug!("{}", msg_str);
+                        let stats = match rolling.get_mut(&stream_name) {
+                            Some(aggregates) => aggregates.stats(Instant::now()),
+                            None => RollingStats::empty(&session_config.rolling),
+                        };
+                        // expired values may have left the window
+                        values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                        let response_message = SessionResponseMessage {
+                            status: stats.count > 0,
+                            status_message: msg_str,
+                            value: stats.mean.unwrap_or(0.),
+                            rolling: Some(stats),
+                            ..SessionResponseMessage::default()
+                        };
+                        session_config.events.aggregate_computed(&stream_name, "rolling", &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "create_stream" => {
+                        let mut response_message = SessionResponseMessage::default();
+                        match streams.entry(stream_name.clone()) {
+                            Entry::Occupied(_) => {
+                                response_message.status_message = format!("[{}] Create stream action, Stream {} already exists", sessionid, stream_name);
+                            }
+                            Entry::Vacant(stream) => match session_config.rolling.with_overrides(&request_message, session_config.max_values) {
+                                Ok(rolling_config) => {
+                                    response_message.status = true;
+                                    response_message.status_message = format!("[{}] Create stream action, Stream {} created. Rolling: {:?}", sessionid, stream_name, rolling_config);
+                                    rolling.insert(stream_name, RollingAggregates::new(rolling_config));
+                                    stream.insert(Vec::new());
+                                    values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                                }
+                                Err(e) => {
+                                    response_message.status_message = format!("[{}] Create stream action, Stream {} not created. {}", sessionid, stream_name, e);
+                                }
+                            },
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "delete_stream" => {
+                        let mut response_message = SessionResponseMessage::default();
+                        match streams.remove(&stream_name) {
+                            Some(values) => {
+                                rolling.remove(&stream_name);
+                                vectors.remove(&stream_name);
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Delete stream action, Stream {} deleted with {} values", sessionid, stream_name, values.len());
+                                values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                            }
+                            None => {
+                                response_message.status_message = format!("[{}] Delete stream action, Stream {} not found", sessionid, stream_name);
+                            }
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "list_streams" => {
+                        let mut stream_infos: Vec<StreamInfo> = streams.iter()
+                            .map(|(name, values)| StreamInfo {
+                                name: name.clone(),
+                                count: values.len(),
+                                vectors: vectors.get(name).map_or(0, Vec::len),
+                            })
+                            .collect();
+                        stream_infos.sort_by(|a, b| a.name.cmp(&b.name));
+                        let response_message = SessionResponseMessage {
+                            status: true,
+                            status_message: format!("[{}] List streams action, {} streams", sessionid, stream_infos.len()),
+                            value: stream_infos.len() as f64,
+                            streams: Some(stream_infos),
+                            ..SessionResponseMessage::default()
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "checkpoint" => {
+                        let label = request_message.checkpoint_label().to_string();
+                        let mut response_message = SessionResponseMessage::default();
+                        let checkpoint_bytes = held_bytes(&streams, &vectors, &rolling);
+                        // saving under a label frees the checkpoint it replaces
+                        if !session_config.memory.can_grow(checkpoint_bytes.saturating_sub(checkpoints.bytes_of(&label))) {
+                            response_message.status_message = format!("[{}] Checkpoint action rejected. Server memory budget exhausted, checkpoint needs {} bytes", sessionid, checkpoint_bytes);
+                            tracing::warn!("{}", response_message.status_message);
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
                         }
-                        send_response(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        let checkpoint = Checkpoint { streams: streams.clone(), vectors: vectors.clone(), rolling: rolling.clone() };
+                        match checkpoints.save(&label, checkpoint, checkpoint_bytes) {
+                            Ok(()) => {
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Checkpoint action, Checkpoint {} saved with {} values", sessionid, label, stored_values(&streams) + vector_slots(&vectors));
+                                values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                            }
+                            Err(e) => {
+                                response_message.status_message = format!("[{}] Checkpoint action, Checkpoint {} not saved. {}", sessionid, label, e);
+                            }
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        response_message.checkpoints = Some(checkpoints.labels());
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "rollback" => {
+                        let label = request_message.checkpoint_label().to_string();
+                        let mut response_message = SessionResponseMessage::default();
+                        match checkpoints.restore(&label) {
+                            Some(checkpoint) => {
+                                // the restored values may take more room than the current ones
+                                let growth = held_bytes(&checkpoint.streams, &checkpoint.vectors, &checkpoint.rolling)
+                                    .saturating_sub(held_bytes(&streams, &vectors, &rolling));
+                                if !session_config.memory.can_grow(growth) {
+                                    response_message.status_message = format!("[{}] Rollback action rejected. Server memory budget exhausted, rollback needs {} bytes", sessionid, growth);
+                                    tracing::warn!("{}", response_message.status_message);
+                                    sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                                    continue;
+                                }
+                                streams = checkpoint.streams;
+                                vectors = checkpoint.vectors;
+                                rolling = checkpoint.rolling;
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Rollback action, Checkpoint {} restored with {} values", sessionid, label, stored_values(&streams) + vector_slots(&vectors));
+                                values_changed(&session_config, &streams, &vectors, &rolling, &checkpoints);
+                            }
+                            None => {
+                                response_message.status_message = format!("[{}] Rollback action, Checkpoint {} not found", sessionid, label);
+                            }
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        response_message.checkpoints = Some(checkpoints.labels());
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "contributions" => {
+                        let response_message = match &group {
+                            Some(group) => SessionResponseMessage {
+                                status: true,
+                                status_message: format!("[{}] Contributions action, quorum {}", sessionid, group.quorum()),
+                                contributions: Some(group.contributions()),
+                                ..SessionResponseMessage::default()
+                            },
+                            None => SessionResponseMessage {
+                                status_message: format!("[{}] Contributions action, Not a group session", sessionid),
+                                ..SessionResponseMessage::default()
+                            },
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "list_checkpoints" => {
+                        let labels = checkpoints.labels();
+                        let response_message = SessionResponseMessage {
+                            status: true,
+                            status_message: format!("[{}] List checkpoints action, {} checkpoints", sessionid, labels.len()),
+                            value: labels.len() as f64,
+                            checkpoints: Some(labels),
+                            ..SessionResponseMessage::default()
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
                         continue;
                     }
                     "shutdown" => {
                         let err_str = format!("[{}] Shutdown action recevied", sessionid);
                         tracing::info!("{}", err_str);
                         let response_message = SessionResponseMessage{status: true, status_message: err_str, ..SessionResponseMessage::default()};
-                        send_response(&sessionid, SessionResponseStatus::SessionExit, response_message, resp);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionExit, response_message, resp);
+                        session_config.events.exiting("shutdown action");
                         break;
                     }
                     _ => {
                         let err_str = format!("[{}] Unknown action. Received message: {:?}", sessionid, request_message);
                         tracing::warn!("{}", err_str);
                         let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
-                        send_response(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
                         continue;
                     }
                 }
@@ -1,24 +1,248 @@
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::oneshot;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use crate::memory::SessionMemory;
+use crate::utils::get_env_or;
+use crate::crypto_pool::CryptoPool;
+use crate::statistics::{Histogram, Summary};
+use crate::rolling::{RollingConfig, RollingStats};
+use crate::homomorphic::PrecisionReport;
+use crate::encryption_params::EncryptionParameters;
+use crate::session_events::SessionEvents;
+use crate::idempotency::IdempotencyPolicy;
+use crate::checkpoint::DEFAULT_CHECKPOINT;
+use crate::group::Contributions;
+use crate::lifecycle::LifecycleBus;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum SessionRequestCommand {
     SessionCommand(String),
     SessionStop,
+    // the session fills the slot with a copy of its state, for a forked session to start from
+    SessionFork(SeedSlot),
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum SessionResponseStatus {
     SessionOk,
     SessionExit,
+    // command rejected because the session has reached one of its limits
+    SessionFull,
 }
 
-#[derive(Deserialize,Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum SessionKind {
+    Open,
+    Encrypted,
+}
+
+// Per-session settings handed to a session when it is spawned
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub max_values: usize,
+    pub memory: SessionMemory,
+    pub crypto_pool: Arc<CryptoPool>,
+    // when set, the session restores from and keeps this snapshot up to date
+    pub snapshot: Option<SnapshotSlot>,
+    // default rolling window and decay of new streams
+    pub rolling: RollingConfig,
+    // encrypted sessions reject parameters estimated below this security level
+    pub min_security_bits: u32,
+    // secret keys are only exported to requests carrying this token, never when unset
+    pub key_export_token: Option<String>,
+    // observers of the session subscribe here
+    pub events: SessionEvents,
+    // server wide events the session raises itself
+    pub lifecycle: LifecycleBus,
+    pub idempotency: IdempotencyPolicy,
+    pub max_checkpoints: usize,
+    // when set, a forked session starts from the state in this slot instead of empty
+    pub seed: Option<SeedSlot>,
+}
+
+// The part of a session's state needed to restart it after a crash: the plain values and vectors of each stream
+#[derive(Debug, Clone, Default)]
+pub struct SessionSnapshot {
+    pub streams: HashMap<String, Vec<f64>>,
+    pub vectors: HashMap<String, Vec<Vec<f64>>>,
+}
+
+pub type SnapshotSlot = Arc<Mutex<SessionSnapshot>>;
+
+pub fn load_snapshot(snapshot: &Option<SnapshotSlot>) -> Option<SessionSnapshot> {
+    // a poisoned lock still holds the last complete snapshot
+    snapshot.as_ref().map(|slot| slot.lock().unwrap_or_else(|e| e.into_inner()).clone())
+}
+
+pub fn publish_snapshot(snapshot: &Option<SnapshotSlot>, streams: &HashMap<String, Vec<f64>>, vectors: &HashMap<String, Vec<Vec<f64>>>) {
+    if let Some(slot) = snapshot {
+        let mut snapshot = slot.lock().unwrap_or_else(|e| e.into_inner());
+        snapshot.streams = streams.clone();
+        snapshot.vectors = vectors.clone();
+    }
+}
+
+// State a forked session starts from, copied from its source session
+pub enum SessionSeed {
+    Open(crate::session::Checkpoint),
+    Encrypted(crate::session_encrypted::EncryptedSeed),
+}
+impl fmt::Debug for SessionSeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionSeed::Open(_) => write!(f, "SessionSeed::Open"),
+            SessionSeed::Encrypted(_) => write!(f, "SessionSeed::Encrypted"),
+        }
+    }
+}
+
+pub type SeedSlot = Arc<Mutex<Option<SessionSeed>>>;
+
+// the seed is taken once, a restarted fork restores from its snapshot instead
+pub fn take_seed(seed: &Option<SeedSlot>) -> Option<SessionSeed> {
+    match seed {
+        Some(slot) => slot.lock().unwrap_or_else(|e| e.into_inner()).take(),
+        None => None,
+    }
+}
+
+// A submitted vector must not be empty and must match the length of the stream's earlier vectors
+pub fn check_vector(vector: &[f64], stream_vector_len: Option<usize>) -> Result<(), String> {
+    if vector.is_empty() {
+        return Err(String::from("Vector must not be empty"));
+    }
+    match stream_vector_len {
+        Some(len) if len != vector.len() => Err(format!("Expected a vector of length {}, got {}", len, vector.len())),
+        _ => Ok(()),
+    }
+}
+
+// Stream used by actions that do not name one
+pub const DEFAULT_STREAM: &str = "default";
+
+// longest client chosen session id
+const MAX_SESSION_ID_LEN: usize = 64;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StreamInfo {
+    pub name: String,
+    pub count: usize,
+    // number of vector submissions
+    pub vectors: usize,
+}
+
+// Base64 text, or raw bytes as the binary wire formats carry them
+struct Base64(String);
+
+impl<'de> Deserialize<'de> for Base64 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Base64Visitor;
+
+        impl<'de> de::Visitor<'de> for Base64Visitor {
+            type Value = Base64;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("base64 text or bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                Ok(Base64(text.to_string()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+                Ok(Base64(base64::encode(bytes)))
+            }
+        }
+
+        deserializer.deserialize_any(Base64Visitor)
+    }
+}
+
+fn base64_field<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<Base64>::deserialize(deserializer)?.map(|encoded| encoded.0))
+}
+
+fn base64_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<Vec<Base64>>::deserialize(deserializer)?.map(|list| list.into_iter().map(|encoded| encoded.0).collect()))
+}
+
+#[derive(Serialize,Deserialize,Debug)]
 pub struct SessionRequestMessage {
     pub action: String,
+    #[serde(default)]
     pub value: f64,
+    // named stream of values the action applies to
+    #[serde(default)]
+    pub stream: Option<String>,
+    // whether a read action clears the stored values, defaults depend on the action
+    #[serde(default)]
+    pub consume: Option<bool>,
+    // percentiles (0 to 100) for the percentile action
+    #[serde(default)]
+    pub percentiles: Option<Vec<f64>>,
+    // number of bins for the histogram action
+    #[serde(default)]
+    pub bins: Option<usize>,
+    // one weight per stored value, for the weighted_mean and dot actions
+    #[serde(default)]
+    pub weights: Option<Vec<f64>>,
+    // rolling window and decay of a stream, for the create_stream action
+    #[serde(default)]
+    pub window_size: Option<usize>,
+    #[serde(default)]
+    pub window_secs: Option<f64>,
+    #[serde(default)]
+    pub half_life_secs: Option<f64>,
+    // payload of the encrypt_vector action, aggregated slot by slot
+    #[serde(default)]
+    pub vector: Option<Vec<f64>>,
+    // largest acceptable error of an encrypted result, results beyond it are flagged
+    #[serde(default)]
+    pub tolerance: Option<f64>,
+    // authorizes the export_key action
+    #[serde(default)]
+    pub token: Option<String>,
+    // base64 encoded bincode ciphertexts for the upload_ciphertexts action, one per value.
+    // CBOR and MessagePack requests may send the bytes themselves.
+    #[serde(default, deserialize_with = "base64_list")]
+    pub ciphertexts: Option<Vec<String>>,
+    // or all of them as one base64 encoded bincode list
+    #[serde(default, deserialize_with = "base64_field")]
+    pub ciphertexts_binary: Option<String>,
+    // single ciphertext to download
+    #[serde(default)]
+    pub index: Option<usize>,
+    // "binary" to download ciphertexts as one list in ciphertexts_binary
+    #[serde(default)]
+    pub encoding: Option<String>,
+    // replays of an action with the same key get the original response, set from the Idempotency-Key header too
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    // position of the action in the client's sequence, starting at 1
+    #[serde(default)]
+    pub seq: Option<u64>,
+    // name of the checkpoint saved or restored by the checkpoint and rollback actions
+    #[serde(default)]
+    pub label: Option<String>,
+    // identifies the participant of a group session sending the action
+    #[serde(default)]
+    pub participant_token: Option<String>,
+}
+impl SessionRequestMessage {
+    pub fn stream_name(&self) -> &str {
+        self.stream.as_deref().unwrap_or(DEFAULT_STREAM)
+    }
+    pub fn checkpoint_label(&self) -> &str {
+        self.label.as_deref().unwrap_or(DEFAULT_CHECKPOINT)
+    }
 }
 
 #[derive(Serialize)]
@@ -26,13 +250,65 @@ pub struct SessionResponseMessage {
     pub status: bool,
     pub status_message: String,
     pub value: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Histogram>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    // bound on the error of a decrypted result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_bound: Option<f64>,
+    // per slot error bounds of a decrypted vector
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_bounds: Option<Vec<f64>>,
+    // precision and noise of an encrypted result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub precision: Option<PrecisionReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<EncryptionParameters>,
+    // json serialized secret key, for the export_key action
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphertexts: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ciphertexts_binary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streams: Option<Vec<StreamInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rolling: Option<RollingStats>,
+    // last sequence number the session applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seq: Option<u64>,
+    // labels of the saved checkpoints, oldest first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkpoints: Option<Vec<String>>,
+    // values contributed by each participant of a group session
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contributions: Option<Vec<Contributions>>,
 }
 impl Default for SessionResponseMessage {
     fn default() -> Self { 
         Self {
             status: false,
             status_message: String::from(""),
-            value: 0.0
+            value: 0.0,
+            values: None,
+            histogram: None,
+            summary: None,
+            error_bound: None,
+            error_bounds: None,
+            precision: None,
+            parameters: None,
+            secret_key: None,
+            ciphertexts: None,
+            ciphertexts_binary: None,
+            streams: None,
+            rolling: None,
+            last_seq: None,
+            checkpoints: None,
+            contributions: None,
         }
     }
 }
@@ -41,6 +317,7 @@ pub fn session_status_to_string(status: &SessionResponseStatus) -> String {
     match status {
         SessionResponseStatus::SessionOk => "SessionOk".into(),
         SessionResponseStatus::SessionExit => "SessionExit".into(),
+        SessionResponseStatus::SessionFull => "SessionFull".into(),
     }
 }
 
@@ -50,21 +327,86 @@ pub type ReceiverSessionResponseChannel = oneshot::Receiver<(SessionResponseStat
 pub type SenderSessionRequestChannel = Sender<(SessionRequestCommand, SenderSessionResponseChannel)>;
 pub type ReceiverSessionRequestChannel = Receiver<(SessionRequestCommand, SenderSessionResponseChannel)>;
 
+// How commands are queued to sessions and how long callers wait for them
+#[derive(Debug, Clone, Copy)]
+pub struct CommandPolicy {
+    pub queue_size: usize,
+    // zero fails immediately when the session queue is full
+    pub enqueue_timeout: Duration,
+    pub execution_timeout: Duration,
+}
+
+impl CommandPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            queue_size: get_env_or("SESSION_QUEUE_SIZE", 100),
+            enqueue_timeout: Duration::from_millis(get_env_or("COMMAND_ENQUEUE_TIMEOUT_MS", 0)),
+            // below the 10s request timeout, so callers get a proper answer
+            execution_timeout: Duration::from_millis(get_env_or("COMMAND_EXECUTION_TIMEOUT_MS", 8000)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    // session queue is full, with the current queue depth
+    QueueFull(usize),
+    // command was queued but the session did not answer in time
+    ExecutionTimeout,
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::QueueFull(queue_depth) => write!(f, "Session queue full. Queue depth: {}", queue_depth),
+            CommandError::ExecutionTimeout => write!(f, "Timed out waiting for session response"),
+            CommandError::Failed(err_msg) => write!(f, "{}", err_msg),
+        }
+    }
+}
+
 pub async fn send_command(
     sessionid: &str, 
     request_channel_tx: SenderSessionRequestChannel,
     cmd: SessionRequestCommand,
-) -> Result<(SessionResponseStatus, String), String> {
+    policy: CommandPolicy,
+) -> Result<(SessionResponseStatus, String), CommandError> {
     tracing::debug!("[{}] Sending SessionCommand.", sessionid);
 
     // create a one time command response channel
     let (resp_tx, resp_rx) = oneshot::channel::<(SessionResponseStatus, String)>();
 
-    // send command on the main communication channel for the session
-    let command_sent = request_channel_tx.send((cmd,resp_tx)).await;
+    // send command on the main communication channel for the session, failing fast
+    // (or after the enqueue timeout) when the session is not keeping up
+    let command_sent = match request_channel_tx.try_send((cmd, resp_tx)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(command)) => {
+            let queue_depth = policy.queue_size.saturating_sub(request_channel_tx.capacity());
+            if policy.enqueue_timeout.as_millis() == 0 {
+                tracing::warn!("[{}] Session queue full. Queue depth: {}", sessionid, queue_depth);
+                return Err(CommandError::QueueFull(queue_depth));
+            }
+            match tokio::time::timeout(policy.enqueue_timeout, request_channel_tx.send(command)).await {
+                Ok(sent) => sent.map_err(|_| ()),
+                Err(_) => {
+                    tracing::warn!("[{}] Session queue full. Queue depth: {}", sessionid, queue_depth);
+                    return Err(CommandError::QueueFull(queue_depth));
+                }
+            }
+        }
+        Err(TrySendError::Closed(_)) => Err(()),
+    };
     match command_sent {
         Ok(()) => {
-            let session_result = resp_rx.await;
+            // dropping resp_rx on timeout lets the session skip the command if it has not started it
+            let session_result = match tokio::time::timeout(policy.execution_timeout, resp_rx).await {
+                Ok(session_result) => session_result,
+                Err(_) => {
+                    tracing::warn!("[{}] Timed out waiting for session response", sessionid);
+                    return Err(CommandError::ExecutionTimeout);
+                }
+            };
             match session_result {
                 Ok((status,response)) => {
                     tracing::debug!("[{}] Received response from session. Status: {}", sessionid, session_status_to_string(&status));
@@ -73,14 +415,14 @@ pub async fn send_command(
                 Err(e) => {
                     let err_msg = format!("[{}] Failed to receive response from session. {}", sessionid, e.to_string());
                     tracing::warn!("{}", err_msg);
-                    Err(err_msg)
+                    Err(CommandError::Failed(err_msg))
                 }
             }
         }
         Err(_) => {
             let err_msg = format!("[{}] Failed to send command to session.", sessionid);
             tracing::warn!("{}", err_msg);
-            Err(err_msg)
+            Err(CommandError::Failed(err_msg))
         }
     }
 }
@@ -106,7 +448,7 @@ pub async fn wait_for_init(sessionid: &str, init_success_rx: ReceiverSessionResp
             tracing::debug!("[{}] Received init response from session. Status: {}", sessionid, session_status_to_string(&init_status));
             match init_status {
                 SessionResponseStatus::SessionOk => Ok(init_response),
-                SessionResponseStatus::SessionExit => {
+                SessionResponseStatus::SessionExit | SessionResponseStatus::SessionFull => {
                     let err_msg = format!("[{}] Init failed for session. {}", sessionid, init_response);
                     tracing::warn!("{}", err_msg);
                     Err(init_response)
@@ -121,10 +463,27 @@ pub async fn wait_for_init(sessionid: &str, init_success_rx: ReceiverSessionResp
     }
 }
 
-pub fn new_session_id(encrypted: bool) -> String {
-    let mut prefix = String::from("open");
-    if encrypted {
-        prefix = String::from("enc");
+/// Session id of a create request carrying an idempotency key, the same key always gives the same id
+pub fn keyed_session_id(idempotency_key: &str) -> String {
+    let key_uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, idempotency_key.as_bytes());
+    String::from("key") + key_uuid.to_simple().encode_lower(&mut Uuid::encode_buffer())
+}
+
+/// Client chosen session ids travel in paths and headers, so they are kept short and plain
+pub fn check_session_id(sessionid: &str) -> Result<(), String> {
+    if sessionid.is_empty() || sessionid.len() > MAX_SESSION_ID_LEN {
+        return Err(format!("Session id must have 1 to {} characters", MAX_SESSION_ID_LEN));
+    }
+    if !sessionid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(String::from("Session id may only contain letters, digits, '-' and '_'"));
     }
+    Ok(())
+}
+
+pub fn new_session_id(kind: SessionKind) -> String {
+    let prefix = match kind {
+        SessionKind::Open => String::from("open"),
+        SessionKind::Encrypted => String::from("enc"),
+    };
     prefix + Uuid::new_v4().to_simple().encode_lower(&mut Uuid::encode_buffer()).to_string().as_str()
 }
\ No newline at end of file
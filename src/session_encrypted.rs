@@ -1,52 +1,343 @@
 use crate::session_common::*;
+use tokio::task::JoinHandle;
 use concrete::*;
-use serde::{Deserialize};
-
-#[derive(Deserialize,Debug)]
-#[serde(default)]
-struct EncryptionParameters { 
-    encoder_min: f64,
-    encoder_max: f64,
-    encoder_precision_bits: usize,
-    encoder_padding_bits: usize,
-    secret_key_dimensions: usize,
-    secret_key_log2_std_dev: i32
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::time::Instant;
+use crate::rolling::{RollingConfig, RollingStats, RollingWindow};
+use std::sync::Arc;
+use crate::memory::plain_values_bytes;
+use crate::crypto_pool::CryptoPool;
+use crate::homomorphic::{self, Bootstrapping, Decrypted};
+use crate::encryption_params;
+use crate::statistics;
+use crate::utils;
+use crate::ciphertext_codec;
+use crate::idempotency::IdempotencyCache;
+use crate::sequence::ActionSequence;
+use crate::checkpoint::Checkpoints;
+use crate::group::{Group, GroupConfig};
+use crate::lifecycle::LifecycleEventKind;
+
+// approximate bytes held by the secret key, the LWE ciphertexts and the plain copies of the values.
+// Both keys and ciphertexts store one 64 bit torus element per dimension, ciphertexts have an extra body element.
+fn encrypted_session_bytes(dimensions: usize, plain_count: usize, ciphertext_count: usize) -> usize {
+    let secret_key_bytes = dimensions * std::mem::size_of::<u64>();
+    let ciphertext_bytes = (dimensions + 1) * std::mem::size_of::<u64>() + std::mem::size_of::<LWE>();
+    secret_key_bytes + ciphertext_count * ciphertext_bytes + plain_values_bytes(plain_count)
+}
+
+// Values of one named stream, with their encryptions
+#[derive(Clone)]
+struct EncryptedStream {
+    values: Vec<f64>,
+    values_encrypted: Vec<LWE>,
+    // most recent ciphertexts, kept apart from the values so mean does not reset them
+    window: RollingWindow<LWE>,
+    // vector submissions, each encrypted as one batch of ciphertexts
+    vectors: Vec<Vec<f64>>,
+    vectors_encrypted: Vec<VectorLWE>,
 }
-impl Default for EncryptionParameters {
-    fn default() -> Self { 
+
+impl EncryptedStream {
+    fn new(rolling_config: RollingConfig) -> Self {
         Self {
-            encoder_min: 0.,
-            encoder_max: 100.,
-            encoder_precision_bits: 16,
-            encoder_padding_bits: 4,
-            secret_key_dimensions: 1024,
-            secret_key_log2_std_dev: -40,
+            values: Vec::new(),
+            values_encrypted: Vec::new(),
+            window: RollingWindow::new(rolling_config),
+            vectors: Vec::new(),
+            vectors_encrypted: Vec::new(),
         }
     }
+
+    // number of values stored, each vector slot counts as a value
+    fn stored_values(&self) -> usize {
+        self.values.len() + self.vectors.iter().map(Vec::len).sum::<usize>()
+    }
+
+    fn push(&mut self, value: f64, encrypted: LWE) {
+        self.values.push(value);
+        self.values_encrypted.push(encrypted);
+    }
+
+    // a copy of the stream with every ciphertext switched to another key
+    fn switch_key(&self, key_switching_key: &LWEKSK) -> Result<Self, String> {
+        let switch_all = |ciphertexts: &[LWE]| -> Result<Vec<LWE>, String> {
+            ciphertexts.iter().map(|ciphertext| homomorphic::switch_key(ciphertext, key_switching_key)).collect()
+        };
+        Ok(Self {
+            values: self.values.clone(),
+            values_encrypted: switch_all(&self.values_encrypted)?,
+            window: self.window.try_map(|ciphertext| homomorphic::switch_key(ciphertext, key_switching_key))?,
+            vectors: self.vectors.clone(),
+            vectors_encrypted: self.vectors_encrypted.iter()
+                .map(|ciphertexts| homomorphic::switch_vector_key(ciphertexts, key_switching_key))
+                .collect::<Result<Vec<VectorLWE>, String>>()?,
+        })
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.values_encrypted.clear();
+    }
+}
+
+// State a forked session starts from: the keys of the source, shared, and a copy of its streams
+pub struct EncryptedSeed {
+    secret_key: Arc<LWESecretKey>,
+    bootstrapping: Option<Arc<Bootstrapping>>,
+    streams: HashMap<String, EncryptedStream>,
+}
+
+// number of values, or of vectors for vector actions, one stream holds
+fn held_by_stream(streams: &HashMap<String, EncryptedStream>, action: &str, stream_name: &str) -> usize {
+    match streams.get(stream_name) {
+        Some(stream) if action == "encrypt_vector" => stream.vectors.len(),
+        Some(stream) => stream.values.len(),
+        None => 0,
+    }
+}
+
+// number of values held across all streams
+fn stored_values(streams: &HashMap<String, EncryptedStream>) -> usize {
+    streams.values().map(EncryptedStream::stored_values).sum()
+}
+
+// number of ciphertexts held across all streams, each vector slot counts as a ciphertext
+fn ciphertext_count(streams: &HashMap<String, EncryptedStream>) -> usize {
+    streams.values()
+        .map(|stream| {
+            let vector_slots: usize = stream.vectors_encrypted.iter().map(|encrypted| encrypted.nb_ciphertexts).sum();
+            stream.values_encrypted.len() + stream.window.len() + vector_slots
+        })
+        .sum()
+}
+
+// approximate bytes held by the streams alone, without the secret key
+fn streams_bytes(dimensions: usize, streams: &HashMap<String, EncryptedStream>) -> usize {
+    encrypted_session_bytes(dimensions, stored_values(streams), ciphertext_count(streams)) - encrypted_session_bytes(dimensions, 0, 0)
+}
+
+// a copy of the streams with every ciphertext switched to another key
+fn switch_streams_key(streams: &HashMap<String, EncryptedStream>, key_switching_key: &LWEKSK) -> Result<HashMap<String, EncryptedStream>, String> {
+    streams.iter()
+        .map(|(name, stream)| Ok((name.clone(), stream.switch_key(key_switching_key)?)))
+        .collect()
+}
+
+// update memory accounting and the restart snapshot after the stored values change
+fn values_changed(
+    session_config: &SessionConfig,
+    dimensions: usize,
+    bootstrap_key_bytes: usize,
+    streams: &HashMap<String, EncryptedStream>,
+    checkpoints: &Checkpoints<HashMap<String, EncryptedStream>>,
+) {
+    session_config.memory.set(
+        encrypted_session_bytes(dimensions, stored_values(streams), ciphertext_count(streams)) + bootstrap_key_bytes + checkpoints.held_bytes()
+    );
+    if session_config.snapshot.is_some() {
+        let plain_streams: HashMap<String, Vec<f64>> = streams.iter()
+            .map(|(name, stream)| (name.clone(), stream.values.clone()))
+            .collect();
+        let plain_vectors: HashMap<String, Vec<Vec<f64>>> = streams.iter()
+            .filter(|(_, stream)| !stream.vectors.is_empty())
+            .map(|(name, stream)| (name.clone(), stream.vectors.clone()))
+            .collect();
+        publish_snapshot(&session_config.snapshot, &plain_streams, &plain_vectors);
+    }
+}
+
+fn encrypt_value(secret_key: &LWESecretKey, encoder: &Encoder, value: f64) -> Result<LWE, String> {
+    // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs#L113
+    // fn LWE::encode_encrypt(.., f64, ..) -> Result<LWE, CryptoAPIError>
+    LWE::encode_encrypt(secret_key, value, encoder).map_err(|e| e.to_string())
+}
+
+// Encrypt a vector as one batch of ciphertexts, one per slot
+fn encrypt_vector(secret_key: &LWESecretKey, encoder: &Encoder, vector: &[f64]) -> Result<VectorLWE, String> {
+    // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs
+    // fn VectorLWE::encode_encrypt(.., &[f64], ..) -> Result<VectorLWE, CryptoAPIError>
+    VectorLWE::encode_encrypt(secret_key, vector, encoder).map_err(|e| e.to_string())
+}
+
+// Run a read only homomorphic computation over stored ciphertexts on the crypto pool.
+// The job shares the ciphertexts instead of copying them, and they return to the stream
+// once it is done, including when it failed or panicked.
+async fn with_ciphertexts<C, F, T>(
+    pool: &CryptoPool,
+    ciphertexts: &mut Vec<C>,
+    job: F,
+) -> Result<T, String>
+where
+    C: Clone + Send + Sync + 'static,
+    F: FnOnce(&[C]) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    let shared_ciphertexts = Arc::new(std::mem::take(ciphertexts));
+    let job_ciphertexts = shared_ciphertexts.clone();
+    let result = pool.run(move || job(&job_ciphertexts)).await;
+    // the job's reference is dropped by the time it returns
+    *ciphertexts = Arc::try_unwrap(shared_ciphertexts).unwrap_or_else(|shared| shared.as_ref().clone());
+    result?
+}
+
+// Homomorphic statistics that leave the stored values in place
+fn homomorphic_statistic(
+    action: &str,
+    values_encrypted: &[LWE],
+    weights: &[f64],
+    secret_key: &LWESecretKey,
+    bootstrapping: Option<&Bootstrapping>,
+) -> Result<Decrypted, String> {
+    match action {
+        "sum" => homomorphic::decrypt(&homomorphic::sum(values_encrypted)?, secret_key),
+        "weighted_mean" => homomorphic::decrypt(&homomorphic::weighted_mean(values_encrypted, weights)?, secret_key),
+        "dot" => homomorphic::decrypt(&homomorphic::dot(values_encrypted, weights)?, secret_key),
+        "variance" => {
+            let bootstrapping = bootstrapping.ok_or_else(|| String::from("Variance needs a session created with bootstrap"))?;
+            let squares = homomorphic::squares(values_encrypted, bootstrapping)?;
+            let mean = homomorphic::decrypt(&homomorphic::mean(values_encrypted, Some(bootstrapping))?, secret_key)?;
+            let mean_of_squares = homomorphic::decrypt(&homomorphic::mean(&squares, Some(bootstrapping))?, secret_key)?;
+            Ok(homomorphic::variance(mean, mean_of_squares))
+        }
+        _ => Err(format!("Unknown homomorphic action {}", action)),
+    }
+}
+
+// Run a crypto job on the pool, abandoning it if the caller stops waiting for the response
+async fn run_for_caller<F, T>(pool: &CryptoPool, resp: &mut SenderSessionResponseChannel, job: F) -> Result<T, String>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::select! {
+        result = pool.run(job) => result,
+        _ = resp.closed() => Err(String::from("Caller went away, crypto job cancelled")),
+    }
+}
+
+// mean and decaying mean of the ciphertexts in a rolling window, oldest first
+fn rolling_statistic(
+    window: &[LWE],
+    decay_weights: Option<Vec<f64>>,
+    secret_key: &LWESecretKey,
+    bootstrapping: Option<&Bootstrapping>,
+    stats: &mut RollingStats,
+) -> Result<Option<Decrypted>, String> {
+    stats.count = window.len();
+    if window.is_empty() {
+        return Ok(None);
+    }
+    let mean = homomorphic::decrypt(&homomorphic::mean(window, bootstrapping)?, secret_key)?;
+    stats.mean = Some(mean.value);
+    if let Some(weights) = decay_weights {
+        // over encrypted values the decaying mean only covers the window
+        stats.decayed_weight = Some(weights.iter().sum());
+        stats.decayed_mean = Some(homomorphic::decrypt(&homomorphic::weighted_mean(window, &weights)?, secret_key)?.value);
+    }
+    Ok(Some(mean))
+}
+
+// The result of the same statistic over the plain values, to report the actual error
+fn plain_reference(action: &str, values: &[f64], weights: &[f64]) -> Option<f64> {
+    match action {
+        "sum" => Some(statistics::sum(values)),
+        "mean" => statistics::mean(values),
+        "variance" => statistics::variance(values),
+        "dot" | "weighted_mean" if weights.len() == values.len() => {
+            let dot: f64 = values.iter().zip(weights).map(|(value, weight)| value * weight).sum();
+            let total: f64 = weights.iter().sum();
+            if action == "dot" {
+                Some(dot)
+            } else if total > 0. {
+                Some(dot / total)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// Set a decrypted result on the response, with its precision report
+fn set_decrypted(
+    response_message: &mut SessionResponseMessage,
+    decrypted: &Decrypted,
+    reference: Option<f64>,
+    tolerance: Option<f64>,
+) {
+    let report = decrypted.report(reference, tolerance);
+    if report.within_tolerance == Some(false) {
+        response_message.status_message.push_str(&format!(
+            ". Error exceeds tolerance {}", tolerance.unwrap_or_default()
+        ));
+    }
+    response_message.value = decrypted.value;
+    response_message.error_bound = Some(decrypted.error_bound);
+    response_message.precision = Some(report);
+}
+
+// Homomorphic mean of the encrypted values, decrypted with the session key.
+// CPU heavy, meant to run on the crypto pool.
+fn encrypted_mean(
+    sessionid: &str,
+    values_encrypted: &[LWE],
+    secret_key: &LWESecretKey,
+    bootstrapping: Option<&Bootstrapping>,
+) -> Result<Decrypted, String> {
+    let encrypted_mean = match homomorphic::mean(values_encrypted, bootstrapping) {
+        Ok(encrypted_mean) => encrypted_mean,
+        Err(e) => return Err(format!("[{}] Mean action, {}", sessionid, e)),
+    };
+    match homomorphic::decrypt(&encrypted_mean, secret_key) {
+        Ok(decrypted_mean) => Ok(decrypted_mean),
+        Err(e) => Err(format!("[{}] Mean action, Failed to decrypt mean. {}", sessionid, e)),
+    }
 }
 
 pub async fn spawn(
     sessionid: String, init_message: String,
     request_channel_rx: ReceiverSessionRequestChannel,
     init_success_tx: SenderSessionResponseChannel,
-) -> () {
+    session_config: SessionConfig,
+) -> JoinHandle<Result<(), ()>> {
     tracing::info!("[{}] Spawning encryted session", sessionid);
     // launch the session loop as a tokio task
-    tokio::spawn(session_loop(sessionid.clone(), request_channel_rx, init_message, init_success_tx));
+    tokio::spawn(session_loop(sessionid.clone(), request_channel_rx, init_message, init_success_tx, session_config))
 }
 
 async fn session_loop(
     sessionid: String, 
     mut request_channel_rx: ReceiverSessionRequestChannel,
     init_message: String,
-    init_success_tx: SenderSessionResponseChannel,
+    mut init_success_tx: SenderSessionResponseChannel,
+    session_config: SessionConfig,
 ) -> Result<(), ()> {
     tracing::debug!("[{}] Starting session loop", sessionid);
 
-    let encryption_parameters: EncryptionParameters = match serde_json::from_str(init_message.as_str()) {
+    // a session shared by a group checks every action against the participant sending it
+    let mut group = match GroupConfig::parse(init_message.as_str()) {
+        Ok(config) => config.map(|config| Group::new(&sessionid, config)),
+        Err(e) => {
+            let status_message = format!("[{}] Session initialized failed. {}", sessionid, e);
+            tracing::warn!("{}", status_message);
+            send_response(
+                &sessionid,
+                SessionResponseStatus::SessionExit,
+                SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                init_success_tx
+            );
+            return Err(());
+        }
+    };
+
+    let (encryption_parameters, imported_key) = match encryption_params::parse(init_message.as_str())
+        .and_then(|parameters| parameters.validate(session_config.max_values, session_config.min_security_bits).map(|_| parameters))
+        .and_then(|parameters| encryption_params::imported_key(init_message.as_str(), &parameters).map(|key| (parameters, key))) {
         Ok(m) => m,
         Err(e) => {
-            let status_message = format!("[{}] Session initialized failed. Failed to json decode encryption parameters. {}", sessionid, e.to_string());
+            let status_message = format!("[{}] Session initialized failed. Invalid encryption parameters. {}", sessionid, e);
             tracing::warn!("{}", status_message);
             send_response(
                 &sessionid, 
@@ -63,7 +354,101 @@ async fn session_loop(
     let secret_key_params = LWEParams::new (
         encryption_parameters.secret_key_dimensions, encryption_parameters.secret_key_log2_std_dev
     );
-    let secret_key = LWESecretKey::new(&secret_key_params);
+    let dimensions = encryption_parameters.secret_key_dimensions;
+    // a fork has the same init message as its source, so the parameters match the seed's keys
+    let seed = match take_seed(&session_config.seed) {
+        Some(SessionSeed::Encrypted(seed)) => Some(seed),
+        _ => None,
+    };
+    let seed_bytes = seed.as_ref().map_or(0, |seed| streams_bytes(dimensions, &seed.streams));
+    if !session_config.memory.can_grow(encrypted_session_bytes(dimensions, 0, 0) + seed_bytes) {
+        let status_message = format!("[{}] Session initialized failed. Server memory budget exhausted", sessionid);
+        tracing::warn!("{}", status_message);
+        send_response(
+            &sessionid,
+            SessionResponseStatus::SessionExit,
+            SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+            init_success_tx
+        );
+        return Err(());
+    }
+    // key generation is the most expensive step of init, keep it off the async workers
+    let key_result = match (&seed, imported_key) {
+        (Some(seed), _) => {
+            tracing::info!("[{}] Using the secret key of the forked session", sessionid);
+            Ok(seed.secret_key.clone())
+        }
+        (None, Some(key)) => {
+            tracing::info!("[{}] Using imported secret key", sessionid);
+            Ok(Arc::new(key))
+        }
+        (None, None) => run_for_caller(
+            &session_config.crypto_pool,
+            &mut init_success_tx,
+            move || LWESecretKey::new(&secret_key_params)
+        ).await.map(Arc::new),
+    };
+    let mut secret_key = match key_result {
+        Ok(key) => key,
+        Err(e) => {
+            let status_message = format!("[{}] Session initialized failed. Unable to generate secret key. {}", sessionid, e);
+            tracing::warn!("{}", status_message);
+            send_response(
+                &sessionid,
+                SessionResponseStatus::SessionExit,
+                SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                init_success_tx
+            );
+            return Err(());
+        }
+    };
+    session_config.memory.set(encrypted_session_bytes(dimensions, 0, 0));
+
+    // bootstrapping keys take far more memory than the session key, sessions opt in to them
+    let (mut bootstrapping, bootstrap_key_bytes) = if encryption_parameters.bootstrap {
+        let bootstrap_key_bytes = Bootstrapping::key_bytes(dimensions);
+        if !session_config.memory.can_grow(bootstrap_key_bytes) {
+            let status_message = format!(
+                "[{}] Session initialized failed. Server memory budget exhausted, bootstrapping keys need {} bytes", sessionid, bootstrap_key_bytes
+            );
+            tracing::warn!("{}", status_message);
+            send_response(
+                &sessionid,
+                SessionResponseStatus::SessionExit,
+                SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                init_success_tx
+            );
+            return Err(());
+        }
+        let job_secret_key = secret_key.clone();
+        let seed_bootstrapping = seed.as_ref().and_then(|seed| seed.bootstrapping.clone());
+        let bootstrapping_result = match seed_bootstrapping {
+            Some(bootstrapping) => Ok(bootstrapping),
+            None => run_for_caller(
+                &session_config.crypto_pool,
+                &mut init_success_tx,
+                move || Bootstrapping::new(&job_secret_key)
+            ).await.map(Arc::new),
+        };
+        match bootstrapping_result {
+            Ok(bootstrapping) => (Some(bootstrapping), bootstrap_key_bytes),
+            Err(e) => {
+                let status_message = format!("[{}] Session initialized failed. Unable to generate bootstrapping keys. {}", sessionid, e);
+                tracing::warn!("{}", status_message);
+                send_response(
+                    &sessionid,
+                    SessionResponseStatus::SessionExit,
+                    SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                    init_success_tx
+                );
+                return Err(());
+            }
+        }
+    } else {
+        (None, 0)
+    };
+    session_config.memory.set(encrypted_session_bytes(dimensions, 0, 0) + bootstrap_key_bytes);
+
 
     // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/encoder/mod.rs#L59
     // fn Encoder::new(..) -> Result<Encoder, CryptoAPIError>
@@ -74,7 +459,13 @@ async fn session_loop(
         encryption_parameters.encoder_padding_bits
     ) {
         Ok(enc) => {
-            let status_message = format!("[{}] Session initialized, with parameters: {:?}", sessionid, encryption_parameters);
+            let status_message = format!(
+                "[{}] Session initialized, with parameters: {:?}. Security: {} bits, mean precision: {} bits",
+                sessionid,
+                encryption_parameters,
+                encryption_params::security_bits(encryption_parameters.secret_key_dimensions, encryption_parameters.secret_key_log2_std_dev),
+                encryption_parameters.mean_precision_bits(session_config.max_values),
+            );
             tracing::info!("{}", status_message);
             if !send_response(
                 &sessionid, 
@@ -100,32 +491,115 @@ async fn session_loop(
     }; 
 
     // Init has succeeded. Start main message loop
-    let mut values: Vec<f64> = Vec::new();
-    let mut values_encrypted: Vec<LWE> = Vec::new();
+    let mut streams: HashMap<String, EncryptedStream> = HashMap::new();
+    // checkpoints are not part of the restart snapshot
+    let mut checkpoints: Checkpoints<HashMap<String, EncryptedStream>> = Checkpoints::new(session_config.max_checkpoints);
+    if let Some(seed) = seed {
+        streams = seed.streams;
+        tracing::info!("[{}] Starting from {} forked values in {} streams", sessionid, stored_values(&streams), streams.len());
+        values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+    } else if let Some(snapshot) = load_snapshot(&session_config.snapshot) {
+        if !snapshot.streams.is_empty() {
+            // a restarted session has a new key, so the snapshot's values are encrypted again
+            let snapshot_len: usize = snapshot.streams.values().map(Vec::len).sum::<usize>()
+                + snapshot.vectors.values().flatten().map(Vec::len).sum::<usize>();
+            tracing::info!("[{}] Restoring {} values in {} streams from snapshot", sessionid, snapshot_len, snapshot.streams.len());
+            let job_secret_key = secret_key.clone();
+            let job_encoder = encoder.clone();
+            let job_rolling_config = session_config.rolling;
+            streams = session_config.crypto_pool.run(move || {
+                let mut restored: HashMap<String, EncryptedStream> = HashMap::new();
+                for (name, stream_values) in snapshot.streams {
+                    let stream = restored.entry(name).or_insert_with(|| EncryptedStream::new(job_rolling_config));
+                    for value in stream_values {
+                        if let Ok(encrypted) = encrypt_value(&job_secret_key, &job_encoder, value) {
+                            stream.push(value, encrypted);
+                        }
+                    }
+                }
+                for (name, stream_vectors) in snapshot.vectors {
+                    let stream = restored.entry(name).or_insert_with(|| EncryptedStream::new(job_rolling_config));
+                    for vector in stream_vectors {
+                        if let Ok(encrypted) = encrypt_vector(&job_secret_key, &job_encoder, &vector) {
+                            stream.vectors.push(vector);
+                            stream.vectors_encrypted.push(encrypted);
+                        }
+                    }
+                }
+                restored
+            }).await.unwrap_or_default();
+            let restored_len = stored_values(&streams);
+            if restored_len < snapshot_len {
+                tracing::warn!("[{}] Restored only {} of {} values from snapshot", sessionid, restored_len, snapshot_len);
+            }
+            values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+        }
+    }
 
+    let mut idempotency = IdempotencyCache::new(session_config.idempotency);
+    let mut sequence = ActionSequence::new();
     while let Some((cmd, resp)) = request_channel_rx.recv().await {
         match cmd {
             SessionRequestCommand::SessionStop => {
                 let status_message = format!("[{}] Stopping session", sessionid);
                 tracing::info!("{}", status_message);
-                if !send_response(
+                if !sequence.respond(
                     &sessionid, 
                     SessionResponseStatus::SessionExit, 
                     SessionResponseMessage{status: true, status_message, ..SessionResponseMessage::default()}, 
                     resp) {
                     tracing::warn!("[{}] Error sending Response for SessionStop", sessionid);
                 }
+                session_config.events.exiting("stopped");
                 break;
             }
 
+            SessionRequestCommand::SessionFork(seed) => {
+                if resp.is_closed() {
+                    tracing::debug!("[{}] Caller went away, skipping SessionFork", sessionid);
+                    continue;
+                }
+                if group.is_some() {
+                    // the fork would hand out new participant tokens for the group's values
+                    let status_message = format!("[{}] Fork refused, group sessions can not be forked", sessionid);
+                    tracing::info!("{}", status_message);
+                    sequence.respond(
+                        &sessionid,
+                        SessionResponseStatus::SessionOk,
+                        SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
+                        resp
+                    );
+                    continue;
+                }
+                // the fork shares the keys and gets its own copy of the ciphertexts
+                let encrypted_seed = EncryptedSeed {
+                    secret_key: secret_key.clone(),
+                    bootstrapping: bootstrapping.clone(),
+                    streams: streams.clone(),
+                };
+                *seed.lock().unwrap_or_else(|e| e.into_inner()) = Some(SessionSeed::Encrypted(encrypted_seed));
+                let status_message = format!("[{}] Fork, {} values copied", sessionid, stored_values(&streams));
+                tracing::info!("{}", status_message);
+                sequence.respond(
+                    &sessionid,
+                    SessionResponseStatus::SessionOk,
+                    SessionResponseMessage{status: true, status_message, ..SessionResponseMessage::default()},
+                    resp
+                );
+            }
+
             SessionRequestCommand::SessionCommand(message) => {
+                if resp.is_closed() {
+                    tracing::debug!("[{}] Caller went away, skipping SessionCommand. Message: {}", sessionid, message);
+                    continue;
+                }
                 tracing::debug!("[{}] Received SessionCommand. Message: {}", sessionid, message);
                 let request_message: SessionRequestMessage = match serde_json::from_str(message.as_str()) {
                     Ok(m) => m,
                     Err(e) => {
                         let status_message = format!("[{}] Failed to json decode request's message field. {}", sessionid, e.to_string());
                         tracing::warn!("{}", status_message);
-                        send_response(
+                        sequence.respond(
                             &sessionid, 
                             SessionResponseStatus::SessionOk, 
                             SessionResponseMessage{status_message, ..SessionResponseMessage::default()},
@@ -136,28 +610,81 @@ async fn session_loop(
                 };
                 
                 tracing::debug!("[{}] Received message: {:?}", sessionid, request_message);
+                let stream_name = request_message.stream_name().to_string();
+                // a replay skips admission, only the participant who sent the action can replay it
+                let mut resp = match idempotency.intercept(
+                    &sessionid,
+                    request_message.idempotency_key.as_deref(),
+                    request_message.participant_token.as_deref(),
+                    resp,
+                ) {
+                    Some(resp) => resp,
+                    None => continue,
+                };
+                let participant = match group.as_mut().map(|group| group.admit(&request_message)).transpose() {
+                    Ok(participant) => participant,
+                    Err(e) => {
+                        let err_str = format!("[{}] Action rejected. {}", sessionid, e);
+                        tracing::debug!("{}", err_str);
+                        let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                };
+                if let Err(e) = sequence.admit(request_message.seq) {
+                    let err_str = format!("[{}] Action rejected. {}", sessionid, e);
+                    tracing::debug!("{}", err_str);
+                    let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                    sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                    continue;
+                }
+                let held_before = held_by_stream(&streams, &request_message.action, &stream_name);
 
                 match request_message.action.as_str() {
                     "encrypt" => {
-                        tracing::debug!("[{}] Encrypt action received. Value {}", sessionid, request_message.value);
+                        let stored = stored_values(&streams);
+                        if stored >= session_config.max_values {
+                            let err_str = format!("[{}] Encrypt action rejected. Session already holds {} values", sessionid, stored);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        // one more ciphertext goes into the stream's rolling window
+                        let additional_bytes = encrypted_session_bytes(dimensions, 1, 2) - encrypted_session_bytes(dimensions, 0, 0);
+                        if !session_config.memory.can_grow(additional_bytes) {
+                            let err_str = format!("[{}] Encrypt action rejected. Server memory budget exhausted", sessionid);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        tracing::debug!("[{}] Encrypt action received. Stream {}, Value {}", sessionid, stream_name, request_message.value);
                         let mut response_message = SessionResponseMessage::default();
 
-                        // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs#L113
-                        // fn LWE::encode_encrypt(.., f64, ..) -> Result<LWE, CryptoAPIError>
-                        let encrypted_val = LWE::encode_encrypt(
-                            &secret_key, 
-                            request_message.value, 
-                            &encoder);
+                        let job_secret_key = secret_key.clone();
+                        let job_encoder = encoder.clone();
+                        let value = request_message.value;
+                        let encrypted_val = run_for_caller(&session_config.crypto_pool, &mut resp, move || {
+                            encrypt_value(&job_secret_key, &job_encoder, value)
+                        }).await.and_then(|encrypted| encrypted);
                         
                         match encrypted_val {
-                            Ok(eval) => {
+                            Ok(encrypted) => {
                                 let msg_str = format!("[{}] Encrypt action, Value {} encrypted successfully", sessionid, request_message.value);
                                 tracing::debug!("{}", msg_str);
                                 response_message.status = true;
                                 response_message.status_message = msg_str;
                                 response_message.value = request_message.value;
-                                values_encrypted.push(eval);
-                                values.push(request_message.value); 
+                                session_config.events.value_received(&stream_name, None, None);
+                                if let (Some(group), Some(participant)) = (group.as_mut(), participant.as_deref()) {
+                                    group.contributed(participant, &request_message, 1, held_before);
+                                }
+                                // streams are created on first use
+                                let stream = streams.entry(stream_name).or_insert_with(|| EncryptedStream::new(session_config.rolling));
+                                stream.window.push(Instant::now(), encrypted.clone());
+                                stream.push(request_message.value, encrypted);
+                                values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
                             }
                             Err(e) => {
                                 let err_str = format!("[{}] Failed to encrypt value. {}", sessionid, e);
@@ -166,109 +693,632 @@ async fn session_loop(
                                 response_message.status_message = err_str;    
                             }
                         }
-                        send_response(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
                         continue;
                     }
                     "mean" => {
-                        let msg_str = format!("[{}] Mean action received", sessionid);
+                        let msg_str = format!("[{}] Mean action received. Stream {}", sessionid, stream_name);
                         tracing::debug!("{}", msg_str);
                         let mut response_message = SessionResponseMessage{status: true, ..SessionResponseMessage::default()};
 
                         // compute the orignal sum and mean for debugging
-                        if values.len() > 0 {
+                        if let Some(stream) = streams.get_mut(&stream_name).filter(|stream| !stream.values.is_empty()) {
                             let mut original_sum: f64 = 0.;
-                            for value in &values {
+                            for value in &stream.values {
                                 original_sum += value;
                             };
-                            let length_multiplier =  1. / (values.len() as f64);
-                            let original_mean = original_sum * length_multiplier;
-
-                            // calculate the mean over encrypted values
-                            let mut encrypted_sum: LWE = values_encrypted.pop().unwrap();
-                            for encrypted_value in &values_encrypted {
-                                // https://github.com/zama-ai/concrete/blob/831095337c7003cc9ddb832c1c85a1455993cd49/concrete/src/lwe/mod.rs#L770
-                                //if let Err(e) = encrypted_sum.add_with_padding_inplace(encrypted_value) {
-                                if let Err(e) = encrypted_sum.add_with_new_min_inplace(encrypted_value, 0.0) {
-                                    let err_str = format!("[{}] Mean action, Failed to add two encrypted values. {}", sessionid, e);
+                            let original_mean = original_sum / (stream.values.len() as f64);
+
+                            // calculate the mean over encrypted values on the crypto pool. The values are
+                            // consumed either way, so the job is not tied to the caller waiting for it.
+                            let job_sessionid = sessionid.clone();
+                            let job_secret_key = secret_key.clone();
+                            let job_bootstrapping = bootstrapping.clone();
+                            let job_values = std::mem::take(&mut stream.values_encrypted);
+                            let mean_result = session_config.crypto_pool.run(move || {
+                                encrypted_mean(&job_sessionid, &job_values, &job_secret_key, job_bootstrapping.as_deref())
+                            }).await.and_then(|mean| mean);
+
+                            match mean_result {
+                                Ok(decrypted_mean) => {
+                                    let msg_str = format!(
+                                        "[{}] Mean action, Mean calculated successfully. Orignal sum: {}, Original mean: {}. Decrypted mean: {}", 
+                                        sessionid,
+                                        original_sum,
+                                        original_mean,
+                                        decrypted_mean.value,
+                                    );
+                                    tracing::debug!("{}", msg_str);
+                                    response_message.status_message = msg_str;
+                                    set_decrypted(&mut response_message, &decrypted_mean, Some(original_mean), request_message.tolerance);
+                                }
+                                Err(err_str) => {
                                     tracing::warn!("{}", err_str);
                                     response_message.status_message = err_str;
                                     response_message.status = false;
-                                    break;
                                 }
                             }
-                            
-                            // try to decrypt the sum, for debugging
-                            let decrypted_sum: f64 = 0.;
-                            /*
-                            if response_message.status {
-                                match encrypted_sum.decrypt_decode(&secret_key) {
-                                    Ok(dsum) => decrypted_sum = dsum,
-                                    Err(e) => {
-                                        let err_str = format!("[{}] Mean action, Failed to decrypt sum. {}", sessionid, e);
-                                        tracing::warn!("{}", err_str);
-                                        response_message.status_message = err_str;
-                                        response_message.status = false;
-                                    }
+                            stream.clear();
+                            values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                        }
+                        session_config.events.aggregate_computed(&stream_name, "mean", &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "sum" | "weighted_mean" | "dot" | "variance" => {
+                        let action = request_message.action.clone();
+                        let msg_str = format!("[{}] {} action received. Stream {}", sessionid, action, stream_name);
+                        tracing::debug!("{}", msg_str);
+                        let mut response_message = SessionResponseMessage{status_message: msg_str, ..SessionResponseMessage::default()};
+
+                        let job_action = action.clone();
+                        let job_secret_key = secret_key.clone();
+                        let job_bootstrapping = bootstrapping.clone();
+                        let weights = request_message.weights.clone().unwrap_or_default();
+                        let reference = streams.get(&stream_name).and_then(|stream| plain_reference(&action, &stream.values, &weights));
+                        let result = match streams.get_mut(&stream_name) {
+                            Some(stream) => with_ciphertexts(
+                                &session_config.crypto_pool,
+                                &mut stream.values_encrypted,
+                                move |encrypted| {
+                                    homomorphic_statistic(&job_action, encrypted, &weights, &job_secret_key, job_bootstrapping.as_deref())
                                 }
+                            ).await,
+                            None => Err(format!("Stream {} not found", stream_name)),
+                        };
+                        match result {
+                            Ok(decrypted) => {
+                                let msg_str = format!(
+                                    "[{}] {} action, calculated successfully. Decrypted result: {}, Error bound: {}",
+                                    sessionid, action, decrypted.value, decrypted.error_bound
+                                );
+                                tracing::debug!("{}", msg_str);
+                                response_message.status = true;
+                                response_message.status_message = msg_str;
+                                set_decrypted(&mut response_message, &decrypted, reference, request_message.tolerance);
                             }
-                            */
+                            Err(e) => {
+                                let err_str = format!("[{}] {} action, {}", sessionid, action, e);
+                                tracing::warn!("{}", err_str);
+                                response_message.status_message = err_str;
+                            }
+                        }
+                        session_config.events.aggregate_computed(&stream_name, &action, &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "encrypt_vector" => {
+                        let vector = request_message.vector.clone().unwrap_or_default();
+                        let stream_vector_len = streams.get(&stream_name).and_then(|stream| stream.vectors.first()).map(Vec::len);
+                        if let Err(e) = check_vector(&vector, stream_vector_len) {
+                            let err_str = format!("[{}] Encrypt vector action rejected. {}", sessionid, e);
+                            tracing::debug!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                            continue;
+                        }
+                        let stored = stored_values(&streams);
+                        if stored + vector.len() > session_config.max_values {
+                            let err_str = format!("[{}] Encrypt vector action rejected. Session already holds {} values", sessionid, stored);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        let additional_bytes = encrypted_session_bytes(dimensions, vector.len(), vector.len()) - encrypted_session_bytes(dimensions, 0, 0);
+                        if !session_config.memory.can_grow(additional_bytes) {
+                            let err_str = format!("[{}] Encrypt vector action rejected. Server memory budget exhausted", sessionid);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        tracing::debug!("[{}] Encrypt vector action received. Stream {}, Length {}", sessionid, stream_name, vector.len());
+                        let mut response_message = SessionResponseMessage::default();
+
+                        let job_secret_key = secret_key.clone();
+                        let job_encoder = encoder.clone();
+                        let job_vector = vector.clone();
+                        let encrypted_vector = run_for_caller(&session_config.crypto_pool, &mut resp, move || {
+                            encrypt_vector(&job_secret_key, &job_encoder, &job_vector)
+                        }).await.and_then(|encrypted| encrypted);
+
+                        match encrypted_vector {
+                            Ok(encrypted) => {
+                                let msg_str = format!("[{}] Encrypt vector action, Vector of length {} encrypted successfully", sessionid, vector.len());
+                                tracing::debug!("{}", msg_str);
+                                response_message.status = true;
+                                response_message.status_message = msg_str;
+                                response_message.value = vector.len() as f64;
+                                session_config.events.value_received(&stream_name, None, Some(vector.len()));
+                                if let (Some(group), Some(participant)) = (group.as_mut(), participant.as_deref()) {
+                                    group.contributed(participant, &request_message, vector.len(), held_before);
+                                }
+                                let stream = streams.entry(stream_name).or_insert_with(|| EncryptedStream::new(session_config.rolling));
+                                stream.vectors.push(vector);
+                                stream.vectors_encrypted.push(encrypted);
+                                values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                            }
+                            Err(e) => {
+                                let err_str = format!("[{}] Failed to encrypt vector. {}", sessionid, e);
+                                tracing::warn!("{}", err_str);
+                                response_message.status_message = err_str;
+                            }
+                        }
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "vector_mean" | "vector_sum" => {
+                        let action = request_message.action.clone();
+                        let msg_str = format!("[{}] {} action received. Stream {}", sessionid, action, stream_name);
+                        tracing::debug!("{}", msg_str);
+                        let mut response_message = SessionResponseMessage{status_message: msg_str, ..SessionResponseMessage::default()};
 
-                            // Calculate the encrypted mean
-                            if response_message.status {
-                                let max_constant: f64 = 1.;
-                                let nb_bit_padding = 4;
-                                if let Err(e) = encrypted_sum.mul_constant_with_padding_inplace(length_multiplier, max_constant, nb_bit_padding) {
-                                    let err_str = format!("[{}] Mean action, Failed to multiply encrypted sum with a float value. {}", sessionid, e);
+                        // like mean, vector_mean clears the vectors unless asked not to
+                        let consume = request_message.consume.unwrap_or(action == "vector_mean");
+                        if let Some(stream) = streams.get_mut(&stream_name).filter(|stream| !stream.vectors_encrypted.is_empty()) {
+                            let vector_count = stream.vectors_encrypted.len();
+                            let job_action = action.clone();
+                            let job_secret_key = secret_key.clone();
+                            // the ciphertexts come back from the pool unless they are consumed
+                            let result = with_ciphertexts(&session_config.crypto_pool, &mut stream.vectors_encrypted, move |encrypted| {
+                                let encrypted = if job_action == "vector_mean" {
+                                    homomorphic::vector_mean(encrypted)
+                                } else {
+                                    homomorphic::vector_sum(encrypted)
+                                };
+                                encrypted.and_then(|encrypted| homomorphic::decrypt_vector(&encrypted, &job_secret_key))
+                            }).await;
+                            match result {
+                                Ok(decrypted) => {
+                                    response_message.status = true;
+                                    response_message.value = vector_count as f64;
+                                    response_message.values = Some(decrypted.iter().map(|slot| slot.value).collect());
+                                    response_message.error_bounds = Some(decrypted.iter().map(|slot| slot.error_bound).collect());
+                                }
+                                Err(e) => {
+                                    let err_str = format!("[{}] {} action, {}", sessionid, action, e);
                                     tracing::warn!("{}", err_str);
                                     response_message.status_message = err_str;
-                                    response_message.status = false;
                                 }
                             }
+                            if consume && response_message.status {
+                                stream.vectors.clear();
+                                stream.vectors_encrypted.clear();
+                            }
+                            values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                        }
+                        session_config.events.aggregate_computed(&stream_name, &action, &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "rolling" => {
+                        let msg_str = format!("[{}] Rolling action received. Stream {}", sessionid, stream_name);
+                        tracing::debug!("{}", msg_str);
+                        let mut response_message = SessionResponseMessage{status_message: msg_str, ..SessionResponseMessage::default()};
+
+                        let now = Instant::now();
+                        let (mut stats, job_window, decay_weights) = match streams.get_mut(&stream_name) {
+                            Some(stream) => {
+                                stream.window.evict(now);
+                                let window: Vec<LWE> = stream.window.items().cloned().collect();
+                                (RollingStats::empty(stream.window.config()), window, stream.window.decay_weights(now))
+                            }
+                            None => (RollingStats::empty(&session_config.rolling), Vec::new(), None),
+                        };
+                        // expired ciphertexts may have left the window
+                        values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
 
-                            let mut decrypted_mean: f64 = 0.;
-                            if response_message.status {
-                                match encrypted_sum.decrypt_decode(&secret_key) {
-                                    Ok(dmean) => decrypted_mean = dmean,
-                                    Err(e) => {
-                                        let err_str = format!("[{}] Mean action, Failed to decrypt mean. {}", sessionid, e);
-                                        tracing::warn!("{}", err_str);
-                                        response_message.status_message = err_str;
-                                        response_message.status = false;
-                                    }
+                        let job_secret_key = secret_key.clone();
+                        let job_bootstrapping = bootstrapping.clone();
+                        let result = run_for_caller(&session_config.crypto_pool, &mut resp, move || {
+                            let mean = rolling_statistic(&job_window, decay_weights, &job_secret_key, job_bootstrapping.as_deref(), &mut stats);
+                            (stats, mean)
+                        }).await;
+                        match result {
+                            Ok((stats, Ok(mean))) => {
+                                response_message.status = stats.count > 0;
+                                if let Some(mean) = mean {
+                                    // the window keeps only ciphertexts, so there is no plain reference
+                                    set_decrypted(&mut response_message, &mean, None, request_message.tolerance);
                                 }
+                                response_message.rolling = Some(stats);
                             }
+                            Ok((_, Err(e))) | Err(e) => {
+                                let err_str = format!("[{}] Rolling action, {}", sessionid, e);
+                                tracing::warn!("{}", err_str);
+                                response_message.status_message = err_str;
+                            }
+                        }
+                        session_config.events.aggregate_computed(&stream_name, "rolling", &response_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "download_ciphertexts" | "download_sum" => {
+                        let action = request_message.action.clone();
+                        tracing::debug!("[{}] {} action received. Stream {}", sessionid, action, stream_name);
+                        let mut response_message = SessionResponseMessage::default();
 
-                            if response_message.status {
-                                let msg_str = format!(
-                                    "[{}] Mean action, Mean calculated successfully. Orignal sum: {}, Original mean: {}. Decrypted sum: {}, Decrypted mean: {}", 
-                                    sessionid,
-                                    original_sum,
-                                    original_mean,
-                                    decrypted_sum,
-                                    decrypted_mean,
+                        let selected: Result<Vec<LWE>, String> = match streams.get_mut(&stream_name) {
+                            None => Err(format!("Stream {} not found", stream_name)),
+                            Some(stream) if action == "download_sum" => {
+                                // the encrypted accumulator, as the sum action computes it
+                                with_ciphertexts(&session_config.crypto_pool, &mut stream.values_encrypted, |encrypted| {
+                                    homomorphic::sum(encrypted).map(|sum| vec![sum])
+                                }).await
+                            }
+                            Some(stream) => match request_message.index {
+                                Some(index) => stream.values_encrypted.get(index)
+                                    .map(|ciphertext| vec![ciphertext.clone()])
+                                    .ok_or_else(|| format!("No ciphertext at index {}, stream holds {}", index, stream.values_encrypted.len())),
+                                None => Ok(stream.values_encrypted.clone()),
+                            },
+                        };
+                        let encoded = selected.and_then(|ciphertexts| {
+                            let count = ciphertexts.len();
+                            if request_message.encoding.as_deref() == Some("binary") {
+                                response_message.ciphertexts_binary = Some(ciphertext_codec::encode_all(&ciphertexts)?);
+                            } else {
+                                response_message.ciphertexts = Some(
+                                    ciphertexts.iter().map(ciphertext_codec::encode).collect::<Result<Vec<String>, String>>()?
                                 );
-                                tracing::debug!("{}", msg_str);
-                                response_message.value = decrypted_mean;
-                                response_message.status_message = msg_str
                             }
-                            values.clear();
-                            values_encrypted.clear();
+                            Ok(count)
+                        });
+                        match encoded {
+                            Ok(count) => {
+                                response_message.status = true;
+                                response_message.value = count as f64;
+                                response_message.status_message = format!("[{}] {} action, {} ciphertexts", sessionid, action, count);
+                            }
+                            Err(e) => {
+                                response_message.status_message = format!("[{}] {} action, {}", sessionid, action, e);
+                                tracing::debug!("{}", response_message.status_message);
+                            }
+                        }
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "upload_ciphertexts" => {
+                        let uploaded: Result<Vec<LWE>, String> = match (&request_message.ciphertexts, &request_message.ciphertexts_binary) {
+                            (Some(ciphertexts), _) => ciphertexts.iter().map(|encoded| ciphertext_codec::decode(encoded)).collect(),
+                            (None, Some(ciphertexts_binary)) => ciphertext_codec::decode_all(ciphertexts_binary),
+                            (None, None) => Err(String::from("No ciphertexts given")),
+                        };
+                        let uploaded = uploaded.and_then(|ciphertexts| {
+                            for (index, ciphertext) in ciphertexts.iter().enumerate() {
+                                ciphertext_codec::check_compatible(ciphertext, dimensions, &encoder)
+                                    .map_err(|e| format!("Ciphertext {}: {}", index, e))?;
+                            }
+                            Ok(ciphertexts)
+                        });
+                        let uploaded = match uploaded {
+                            Ok(ciphertexts) => ciphertexts,
+                            Err(e) => {
+                                let err_str = format!("[{}] Upload ciphertexts action rejected. {}", sessionid, e);
+                                tracing::debug!("{}", err_str);
+                                let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                                sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                                continue;
+                            }
+                        };
+                        let stored = stored_values(&streams);
+                        if stored + uploaded.len() > session_config.max_values {
+                            let err_str = format!("[{}] Upload ciphertexts action rejected. Session already holds {} values", sessionid, stored);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        let additional_bytes = encrypted_session_bytes(dimensions, uploaded.len(), uploaded.len() * 2)
+                            - encrypted_session_bytes(dimensions, 0, 0);
+                        if !session_config.memory.can_grow(additional_bytes) {
+                            let err_str = format!("[{}] Upload ciphertexts action rejected. Server memory budget exhausted", sessionid);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        tracing::debug!("[{}] Upload ciphertexts action received. Stream {}, Count {}", sessionid, stream_name, uploaded.len());
+                        let mut response_message = SessionResponseMessage::default();
+
+                        // the session keeps the plain value of every ciphertext
+                        let job_secret_key = secret_key.clone();
+                        let accepted = run_for_caller(&session_config.crypto_pool, &mut resp, move || {
+                            uploaded.into_iter().map(|ciphertext| {
+                                let value = homomorphic::decrypt(&ciphertext, &job_secret_key)?.value;
+                                Ok((value, ciphertext))
+                            }).collect::<Result<Vec<(f64, LWE)>, String>>()
+                        }).await.and_then(|accepted| accepted);
+
+                        match accepted {
+                            Ok(accepted) => {
+                                let count = accepted.len();
+                                session_config.events.values_received(&stream_name, count);
+                                if let (Some(group), Some(participant)) = (group.as_mut(), participant.as_deref()) {
+                                    group.contributed(participant, &request_message, count, held_before);
+                                }
+                                let now = Instant::now();
+                                let stream = streams.entry(stream_name).or_insert_with(|| EncryptedStream::new(session_config.rolling));
+                                for (value, encrypted) in accepted {
+                                    stream.window.push(now, encrypted.clone());
+                                    stream.push(value, encrypted);
+                                }
+                                values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                                response_message.status = true;
+                                response_message.value = count as f64;
+                                response_message.status_message = format!("[{}] Upload ciphertexts action, {} ciphertexts stored", sessionid, count);
+                            }
+                            Err(e) => {
+                                response_message.status_message = format!("[{}] Upload ciphertexts action failed. {}", sessionid, e);
+                                tracing::warn!("{}", response_message.status_message);
+                            }
+                        }
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "export_parameters" => {
+                        let response_message = SessionResponseMessage {
+                            status: true,
+                            status_message: format!(
+                                "[{}] Export parameters action. Security: {} bits, mean precision: {} bits",
+                                sessionid,
+                                encryption_params::security_bits(encryption_parameters.secret_key_dimensions, encryption_parameters.secret_key_log2_std_dev),
+                                encryption_parameters.mean_precision_bits(session_config.max_values),
+                            ),
+                            parameters: Some(encryption_parameters.clone()),
+                            ..SessionResponseMessage::default()
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "export_key" => {
+                        let authorized = match (&session_config.key_export_token, &request_message.token) {
+                            (Some(expected), Some(token)) => utils::constant_time_eq(expected, token),
+                            _ => false,
+                        };
+                        let mut response_message = SessionResponseMessage::default();
+                        if !authorized {
+                            response_message.status_message = format!("[{}] Export key action rejected. Missing or invalid token", sessionid);
+                            tracing::warn!("{}", response_message.status_message);
+                        } else {
+                            match serde_json::to_string(secret_key.as_ref()) {
+                                Ok(serialized) => {
+                                    response_message.status = true;
+                                    response_message.status_message = format!("[{}] Export key action, Secret key exported", sessionid);
+                                    response_message.parameters = Some(encryption_parameters.clone());
+                                    response_message.secret_key = Some(serialized);
+                                    tracing::info!("{}", response_message.status_message);
+                                    session_config.lifecycle.session(LifecycleEventKind::Migrated, &sessionid, SessionKind::Encrypted, "Secret key exported");
+                                }
+                                Err(e) => {
+                                    response_message.status_message = format!("[{}] Export key action, Failed to serialize secret key. {}", sessionid, e);
+                                    tracing::warn!("{}", response_message.status_message);
+                                }
+                            }
+                        }
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "rotate_key" => {
+                        let key_switching_bytes = homomorphic::key_switching_key_bytes(dimensions, dimensions);
+                        if !session_config.memory.can_grow(key_switching_bytes) {
+                            let err_str = format!("[{}] Rotate key action rejected. Server memory budget exhausted, key switching needs {} bytes", sessionid, key_switching_bytes);
+                            tracing::warn!("{}", err_str);
+                            let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        tracing::info!("[{}] Rotate key action received", sessionid);
+                        let mut response_message = SessionResponseMessage::default();
+
+                        // the stored ciphertexts move to the pool and come back switched to the new key,
+                        // or unchanged if any of them fails to switch. The job runs to completion even
+                        // if the caller goes away, the session state depends on it.
+                        let job_secret_key = secret_key.clone();
+                        let job_secret_key_params = LWEParams::new(
+                            encryption_parameters.secret_key_dimensions, encryption_parameters.secret_key_log2_std_dev
+                        );
+                        let job_with_bootstrapping = bootstrapping.is_some();
+                        // checkpoints are switched too, so a later rollback decrypts under the new key
+                        let job_streams = std::mem::take(&mut streams);
+                        let job_checkpoints = std::mem::replace(&mut checkpoints, Checkpoints::new(session_config.max_checkpoints));
+                        let result = session_config.crypto_pool.run(move || {
+                            let new_secret_key = LWESecretKey::new(&job_secret_key_params);
+                            let key_switching_key = homomorphic::key_switching_key(&job_secret_key, &new_secret_key);
+                            let rotated = switch_streams_key(&job_streams, &key_switching_key).and_then(|rotated| {
+                                let rotated_checkpoints = job_checkpoints.try_map(|saved| switch_streams_key(saved, &key_switching_key))?;
+                                Ok((rotated, rotated_checkpoints))
+                            });
+                            match rotated {
+                                Ok(rotated) => {
+                                    let new_bootstrapping = if job_with_bootstrapping {
+                                        Some(Bootstrapping::new(&new_secret_key))
+                                    } else {
+                                        None
+                                    };
+                                    (rotated, Ok((new_secret_key, new_bootstrapping)))
+                                }
+                                Err(e) => ((job_streams, job_checkpoints), Err(e)),
+                            }
+                        }).await;
+                        match result {
+                            Ok(((job_streams, job_checkpoints), Ok((new_secret_key, new_bootstrapping)))) => {
+                                streams = job_streams;
+                                checkpoints = job_checkpoints;
+                                secret_key = Arc::new(new_secret_key);
+                                bootstrapping = new_bootstrapping.map(Arc::new);
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Rotate key action, Key rotated, {} values and {} checkpoints switched", sessionid, stored_values(&streams), checkpoints.len());
+                                tracing::info!("{}", response_message.status_message);
+                            }
+                            Ok(((job_streams, job_checkpoints), Err(e))) => {
+                                streams = job_streams;
+                                checkpoints = job_checkpoints;
+                                response_message.status_message = format!("[{}] Rotate key action, Key kept. {}", sessionid, e);
+                                tracing::warn!("{}", response_message.status_message);
+                            }
+                            Err(e) => {
+                                // the ciphertexts were lost with the job
+                                response_message.status_message = format!("[{}] Rotate key action failed, stored values and checkpoints lost. {}", sessionid, e);
+                                tracing::warn!("{}", response_message.status_message);
+                            }
+                        }
+                        values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "create_stream" => {
+                        let mut response_message = SessionResponseMessage::default();
+                        match streams.entry(stream_name.clone()) {
+                            Entry::Occupied(_) => {
+                                response_message.status_message = format!("[{}] Create stream action, Stream {} already exists", sessionid, stream_name);
+                            }
+                            Entry::Vacant(stream) => match session_config.rolling.with_overrides(&request_message, session_config.max_values) {
+                                Ok(rolling_config) => {
+                                    response_message.status = true;
+                                    response_message.status_message = format!("[{}] Create stream action, Stream {} created. Rolling: {:?}", sessionid, stream_name, rolling_config);
+                                    stream.insert(EncryptedStream::new(rolling_config));
+                                    values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                                }
+                                Err(e) => {
+                                    response_message.status_message = format!("[{}] Create stream action, Stream {} not created. {}", sessionid, stream_name, e);
+                                }
+                            },
                         }
-                        send_response(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        tracing::debug!("{}", response_message.status_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "delete_stream" => {
+                        let mut response_message = SessionResponseMessage::default();
+                        match streams.remove(&stream_name) {
+                            Some(stream) => {
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Delete stream action, Stream {} deleted with {} values", sessionid, stream_name, stream.values.len());
+                                values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                            }
+                            None => {
+                                response_message.status_message = format!("[{}] Delete stream action, Stream {} not found", sessionid, stream_name);
+                            }
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "list_streams" => {
+                        let mut stream_infos: Vec<StreamInfo> = streams.iter()
+                            .map(|(name, stream)| StreamInfo {
+                                name: name.clone(),
+                                count: stream.values.len(),
+                                vectors: stream.vectors.len(),
+                            })
+                            .collect();
+                        stream_infos.sort_by(|a, b| a.name.cmp(&b.name));
+                        let response_message = SessionResponseMessage {
+                            status: true,
+                            status_message: format!("[{}] List streams action, {} streams", sessionid, stream_infos.len()),
+                            value: stream_infos.len() as f64,
+                            streams: Some(stream_infos),
+                            ..SessionResponseMessage::default()
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "checkpoint" => {
+                        let label = request_message.checkpoint_label().to_string();
+                        let mut response_message = SessionResponseMessage::default();
+                        let checkpoint_bytes = streams_bytes(dimensions, &streams);
+                        // saving under a label frees the checkpoint it replaces
+                        if !session_config.memory.can_grow(checkpoint_bytes.saturating_sub(checkpoints.bytes_of(&label))) {
+                            response_message.status_message = format!("[{}] Checkpoint action rejected. Server memory budget exhausted, checkpoint needs {} bytes", sessionid, checkpoint_bytes);
+                            tracing::warn!("{}", response_message.status_message);
+                            sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                            continue;
+                        }
+                        match checkpoints.save(&label, streams.clone(), checkpoint_bytes) {
+                            Ok(()) => {
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Checkpoint action, Checkpoint {} saved with {} values", sessionid, label, stored_values(&streams));
+                                values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                            }
+                            Err(e) => {
+                                response_message.status_message = format!("[{}] Checkpoint action, Checkpoint {} not saved. {}", sessionid, label, e);
+                            }
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        response_message.checkpoints = Some(checkpoints.labels());
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "rollback" => {
+                        let label = request_message.checkpoint_label().to_string();
+                        let mut response_message = SessionResponseMessage::default();
+                        match checkpoints.restore(&label) {
+                            Some(saved_streams) => {
+                                // the restored values may take more room than the current ones
+                                let growth = streams_bytes(dimensions, &saved_streams).saturating_sub(streams_bytes(dimensions, &streams));
+                                if !session_config.memory.can_grow(growth) {
+                                    response_message.status_message = format!("[{}] Rollback action rejected. Server memory budget exhausted, rollback needs {} bytes", sessionid, growth);
+                                    tracing::warn!("{}", response_message.status_message);
+                                    sequence.respond(&sessionid, SessionResponseStatus::SessionFull, response_message, resp);
+                                    continue;
+                                }
+                                streams = saved_streams;
+                                response_message.status = true;
+                                response_message.status_message = format!("[{}] Rollback action, Checkpoint {} restored with {} values", sessionid, label, stored_values(&streams));
+                                values_changed(&session_config, dimensions, bootstrap_key_bytes, &streams, &checkpoints);
+                            }
+                            None => {
+                                response_message.status_message = format!("[{}] Rollback action, Checkpoint {} not found", sessionid, label);
+                            }
+                        }
+                        tracing::debug!("{}", response_message.status_message);
+                        response_message.checkpoints = Some(checkpoints.labels());
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "contributions" => {
+                        let response_message = match &group {
+                            Some(group) => SessionResponseMessage {
+                                status: true,
+                                status_message: format!("[{}] Contributions action, quorum {}", sessionid, group.quorum()),
+                                contributions: Some(group.contributions()),
+                                ..SessionResponseMessage::default()
+                            },
+                            None => SessionResponseMessage {
+                                status_message: format!("[{}] Contributions action, Not a group session", sessionid),
+                                ..SessionResponseMessage::default()
+                            },
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        continue;
+                    }
+                    "list_checkpoints" => {
+                        let labels = checkpoints.labels();
+                        let response_message = SessionResponseMessage {
+                            status: true,
+                            status_message: format!("[{}] List checkpoints action, {} checkpoints", sessionid, labels.len()),
+                            value: labels.len() as f64,
+                            checkpoints: Some(labels),
+                            ..SessionResponseMessage::default()
+                        };
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
                         continue;
                     }
                     "shutdown" => {
                         let err_str = format!("[{}] Shutdown action recevied", sessionid);
                         tracing::info!("{}", err_str);
                         let response_message = SessionResponseMessage{status: true, status_message: err_str, ..SessionResponseMessage::default()};
-                        send_response(&sessionid, SessionResponseStatus::SessionExit, response_message, resp);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionExit, response_message, resp);
+                        session_config.events.exiting("shutdown action");
                         break;
                     }
                     _ => {
                         let err_str = format!("[{}] Unknown action. Received message: {:?}", sessionid, request_message);
                         tracing::warn!("{}", err_str);
                         let response_message = SessionResponseMessage{status_message: err_str, ..SessionResponseMessage::default()};
-                        send_response(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
+                        sequence.respond(&sessionid, SessionResponseStatus::SessionOk, response_message, resp);
                         continue;
                     }
                 }
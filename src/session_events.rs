@@ -0,0 +1,95 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::rolling::RollingStats;
+use crate::session_common::SessionResponseMessage;
+
+// events kept for observers that fall behind, older ones are dropped and reported as lagged
+const EVENTS_BUFFER: usize = 64;
+
+/// What observers of a session see, as published by the session loop
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    ValueReceived {
+        stream: String,
+        // number of values, more than one for uploads
+        count: usize,
+        // encrypted sessions never publish plain values
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        vector_len: Option<usize>,
+    },
+    AggregateComputed {
+        stream: String,
+        action: String,
+        value: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        values: Option<Vec<f64>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rolling: Option<RollingStats>,
+    },
+    SessionExiting {
+        reason: String,
+    },
+}
+
+impl SessionEvent {
+    /// the sse event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionEvent::ValueReceived { .. } => "value_received",
+            SessionEvent::AggregateComputed { .. } => "aggregate_computed",
+            SessionEvent::SessionExiting { .. } => "session_exiting",
+        }
+    }
+}
+
+/// Broadcast channel of a session's events. It lives in the session config, so observers
+/// keep their subscription when a crashed session is restarted.
+#[derive(Debug, Clone)]
+pub struct SessionEvents {
+    tx: broadcast::Sender<SessionEvent>,
+}
+
+impl SessionEvents {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENTS_BUFFER);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish(&self, event: SessionEvent) {
+        // sending only fails when nobody is observing
+        let _ = self.tx.send(event);
+    }
+
+    pub fn value_received(&self, stream: &str, value: Option<f64>, vector_len: Option<usize>) {
+        self.publish(SessionEvent::ValueReceived { stream: stream.to_string(), count: 1, value, vector_len });
+    }
+
+    pub fn values_received(&self, stream: &str, count: usize) {
+        self.publish(SessionEvent::ValueReceived { stream: stream.to_string(), count, value: None, vector_len: None });
+    }
+
+    /// publish the result of an aggregate action, if it succeeded
+    pub fn aggregate_computed(&self, stream: &str, action: &str, response_message: &SessionResponseMessage) {
+        if !response_message.status {
+            return;
+        }
+        self.publish(SessionEvent::AggregateComputed {
+            stream: stream.to_string(),
+            action: action.to_string(),
+            value: response_message.value,
+            values: response_message.values.clone(),
+            rolling: response_message.rolling.clone(),
+        });
+    }
+
+    pub fn exiting(&self, reason: &str) {
+        self.publish(SessionEvent::SessionExiting { reason: reason.to_string() });
+    }
+}
@@ -0,0 +1,228 @@
+use serde::Serialize;
+use crate::session_common::{SessionRequestMessage, SessionResponseMessage};
+
+/// Equal width histogram over `[min, max]`, with `counts.len() + 1` edges
+#[derive(Serialize, Debug, Clone)]
+pub struct Histogram {
+    pub edges: Vec<f64>,
+    pub counts: Vec<usize>,
+}
+
+/// All summary statistics at once
+#[derive(Serialize, Debug, Clone)]
+pub struct Summary {
+    pub count: usize,
+    pub sum: f64,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub variance: f64,
+    pub stddev: f64,
+    pub median: f64,
+}
+
+pub fn sum(values: &[f64]) -> f64 {
+    values.iter().sum()
+}
+
+pub fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(sum(values) / (values.len() as f64))
+}
+
+pub fn min(values: &[f64]) -> Option<f64> {
+    values.iter().cloned().fold(None, |acc, v| Some(acc.map_or(v, |m: f64| m.min(v))))
+}
+
+pub fn max(values: &[f64]) -> Option<f64> {
+    values.iter().cloned().fold(None, |acc, v| Some(acc.map_or(v, |m: f64| m.max(v))))
+}
+
+/// population variance
+pub fn variance(values: &[f64]) -> Option<f64> {
+    let mean = mean(values)?;
+    let squared_deviations: f64 = values.iter().map(|v| (v - mean) * (v - mean)).sum();
+    Some(squared_deviations / (values.len() as f64))
+}
+
+pub fn stddev(values: &[f64]) -> Option<f64> {
+    variance(values).map(f64::sqrt)
+}
+
+fn sorted(values: &[f64]) -> Vec<f64> {
+    let mut sorted_values = values.to_vec();
+    sorted_values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    sorted_values
+}
+
+// percentile `p` (0 to 100) of already sorted values, interpolating between closest ranks
+fn percentile_of_sorted(sorted_values: &[f64], p: f64) -> Option<f64> {
+    if sorted_values.is_empty() || !(0. ..=100.).contains(&p) {
+        return None;
+    }
+    let rank = p / 100. * ((sorted_values.len() - 1) as f64);
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let fraction = rank - (lower as f64);
+    Some(sorted_values[lower] + (sorted_values[upper] - sorted_values[lower]) * fraction)
+}
+
+pub fn percentiles(values: &[f64], ps: &[f64]) -> Option<Vec<f64>> {
+    let sorted_values = sorted(values);
+    ps.iter().map(|p| percentile_of_sorted(&sorted_values, *p)).collect()
+}
+
+pub fn median(values: &[f64]) -> Option<f64> {
+    percentile_of_sorted(&sorted(values), 50.)
+}
+
+pub fn histogram(values: &[f64], bins: usize) -> Option<Histogram> {
+    let (low, high) = (min(values)?, max(values)?);
+    if bins == 0 {
+        return None;
+    }
+    let width = (high - low) / (bins as f64);
+    let edges = (0..=bins).map(|i| low + width * (i as f64)).collect();
+    let mut counts = vec![0; bins];
+    for value in values {
+        let bin = if width > 0. { ((value - low) / width) as usize } else { 0 };
+        // the max value belongs to the last bin
+        counts[bin.min(bins - 1)] += 1;
+    }
+    Some(Histogram { edges, counts })
+}
+
+pub fn summary(values: &[f64]) -> Option<Summary> {
+    let variance = variance(values)?;
+    Some(Summary {
+        count: values.len(),
+        sum: sum(values),
+        mean: mean(values)?,
+        min: min(values)?,
+        max: max(values)?,
+        variance,
+        stddev: variance.sqrt(),
+        median: median(values)?,
+    })
+}
+
+const STATISTICS_ACTIONS: &[&str] = &[
+    "sum", "count", "min", "max", "variance", "stddev", "median", "percentile", "histogram", "summary",
+];
+
+/// slot by slot sum of vectors of the same length
+pub fn vector_sum(vectors: &[Vec<f64>]) -> Option<Vec<f64>> {
+    let mut sums = vec![0.; vectors.first()?.len()];
+    for vector in vectors {
+        for (sum, value) in sums.iter_mut().zip(vector) {
+            *sum += value;
+        }
+    }
+    Some(sums)
+}
+
+/// slot by slot mean of vectors of the same length
+pub fn vector_mean(vectors: &[Vec<f64>]) -> Option<Vec<f64>> {
+    let count = vectors.len() as f64;
+    vector_sum(vectors).map(|sums| sums.into_iter().map(|sum| sum / count).collect())
+}
+
+pub fn is_statistics_action(action: &str) -> bool {
+    STATISTICS_ACTIONS.contains(&action)
+}
+
+/// Fill `response_message` with the statistic named by `action`, computed over `values`
+pub fn compute(
+    action: &str,
+    values: &[f64],
+    request_message: &SessionRequestMessage,
+    response_message: &mut SessionResponseMessage,
+) -> Result<(), String> {
+    let no_values = || String::from("No values");
+    match action {
+        "sum" => response_message.value = sum(values),
+        "count" => response_message.value = values.len() as f64,
+        "min" => response_message.value = min(values).ok_or_else(no_values)?,
+        "max" => response_message.value = max(values).ok_or_else(no_values)?,
+        "variance" => response_message.value = variance(values).ok_or_else(no_values)?,
+        "stddev" => response_message.value = stddev(values).ok_or_else(no_values)?,
+        "median" => response_message.value = median(values).ok_or_else(no_values)?,
+        "percentile" => {
+            let ps = request_message.percentiles.clone().unwrap_or_else(|| vec![50.]);
+            let results = percentiles(values, &ps)
+                .ok_or_else(|| String::from("No values, or percentiles outside 0 to 100"))?;
+            response_message.value = results.first().copied().unwrap_or(0.);
+            response_message.values = Some(results);
+        }
+        "histogram" => {
+            let bins = request_message.bins.unwrap_or(10);
+            let result = histogram(values, bins).ok_or_else(|| String::from("No values, or zero bins"))?;
+            response_message.histogram = Some(result);
+        }
+        "summary" => {
+            let result = summary(values).ok_or_else(no_values)?;
+            response_message.value = result.mean;
+            response_message.summary = Some(result);
+        }
+        _ => return Err(format!("Unknown statistics action {}", action)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_interpolate_between_closest_ranks() {
+        let values = [4., 1., 3., 2.];
+        assert_eq!(percentiles(&values, &[0., 100.]), Some(vec![1., 4.]));
+        // rank 1.5 lies half way between 2 and 3
+        assert_eq!(median(&values), Some(2.5));
+        // rank 0.75 of the sorted values
+        assert_eq!(percentiles(&values, &[25.]), Some(vec![1.75]));
+        assert_eq!(percentiles(&[7.], &[0., 50., 100.]), Some(vec![7., 7., 7.]));
+    }
+
+    #[test]
+    fn percentiles_outside_0_to_100_or_without_values_fail() {
+        assert_eq!(percentiles(&[1., 2.], &[50., 101.]), None);
+        assert_eq!(percentiles(&[1., 2.], &[-1.]), None);
+        assert_eq!(percentiles(&[], &[50.]), None);
+        assert_eq!(median(&[]), None);
+    }
+
+    #[test]
+    fn histogram_edges_span_min_to_max() {
+        let result = histogram(&[0., 1., 2., 3., 4.], 4).unwrap();
+        assert_eq!(result.edges, vec![0., 1., 2., 3., 4.]);
+        // values on an inner edge go to the upper bin, the max to the last bin
+        assert_eq!(result.counts, vec![1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn histogram_of_equal_values_has_one_full_bin() {
+        let result = histogram(&[5., 5., 5.], 3).unwrap();
+        assert_eq!(result.edges, vec![5., 5., 5., 5.]);
+        assert_eq!(result.counts, vec![3, 0, 0]);
+    }
+
+    #[test]
+    fn histogram_needs_values_and_bins() {
+        assert!(histogram(&[], 3).is_none());
+        assert!(histogram(&[1., 2.], 0).is_none());
+    }
+
+    #[test]
+    fn summary_uses_the_population_variance() {
+        let result = summary(&[2., 4., 4., 4., 5., 5., 7., 9.]).unwrap();
+        assert_eq!(result.count, 8);
+        assert_eq!(result.mean, 5.);
+        assert_eq!(result.variance, 4.);
+        assert_eq!(result.stddev, 2.);
+        assert_eq!((result.min, result.max, result.median), (2., 9., 4.5));
+        assert!(summary(&[]).is_none());
+    }
+}
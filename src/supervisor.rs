@@ -0,0 +1,61 @@
+use std::collections::VecDeque;
+use tokio::task::JoinError;
+use crate::utils::get_env_or;
+
+/// What to do when a session task dies
+#[derive(Debug, Clone, Copy)]
+pub struct SupervisorPolicy {
+    // restart panicked sessions from their last snapshot, sessions keep a snapshot only when enabled
+    pub restart_on_panic: bool,
+    pub max_restarts: usize,
+    // number of failed sessions remembered, so callers get the failure instead of "not found"
+    pub max_failed_sessions: usize,
+}
+
+impl SupervisorPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            restart_on_panic: get_env_or("SESSION_RESTART_ON_PANIC", false),
+            max_restarts: get_env_or("SESSION_MAX_RESTARTS", 3),
+            max_failed_sessions: get_env_or("MAX_FAILED_SESSIONS", 100),
+        }
+    }
+}
+
+/// Bounded record of sessions whose task failed, oldest evicted first
+#[derive(Debug, Default)]
+pub struct FailedSessions {
+    failures: VecDeque<(String, String)>,
+}
+
+impl FailedSessions {
+    pub fn record(&mut self, sessionid: String, failure: String, max_failed_sessions: usize) {
+        self.failures.retain(|(failed_sessionid, _)| *failed_sessionid != sessionid);
+        self.failures.push_back((sessionid, failure));
+        while self.failures.len() > max_failed_sessions {
+            self.failures.pop_front();
+        }
+    }
+
+    pub fn get(&self, sessionid: &str) -> Option<&String> {
+        self.failures.iter()
+            .find(|(failed_sessionid, _)| failed_sessionid == sessionid)
+            .map(|(_, failure)| failure)
+    }
+}
+
+/// describe why a session task ended abnormally, extracting the panic message when there is one
+pub fn task_failure_message(join_error: JoinError) -> String {
+    match join_error.try_into_panic() {
+        Ok(payload) => {
+            if let Some(msg) = payload.downcast_ref::<&str>() {
+                format!("Session panicked. {}", msg)
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                format!("Session panicked. {}", msg)
+            } else {
+                String::from("Session panicked")
+            }
+        }
+        Err(join_error) => format!("Session task failed. {}", join_error),
+    }
+}
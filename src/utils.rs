@@ -16,4 +16,26 @@ pub fn get_local_ip_address() -> Option<String> {
         Ok(addr) => return Some(addr.ip().to_string()),
         Err(_) => return None,
     };
-}
\ No newline at end of file
+}
+
+/// read and parse an environment variable, falling back to `default` when it is unset or invalid
+pub fn get_env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => match value.parse::<T>() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                tracing::warn!("Invalid value for {}: {}, using default", name, value);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// compare two secrets in time independent of where they first differ
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes().zip(b.bytes()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,139 @@
+use bytes::Bytes;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{client::HttpConnector, Body, Client, Method, Request, StatusCode};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use crate::lifecycle::LifecycleBus;
+use crate::utils::get_env_or;
+
+/// Where and how lifecycle events are POSTed
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    // signs every delivery with HMAC-SHA256 when set
+    pub secret: Option<String>,
+    pub max_retries: usize,
+    // delay before the first retry, doubled after each attempt
+    pub backoff_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let urls = std::env::var("WEBHOOK_URLS").unwrap_or_default()
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .filter(|url| {
+                // the hyper client is built without tls
+                let supported = url.starts_with("http://");
+                if !supported {
+                    tracing::warn!("Ignoring webhook url {}, only http:// urls are supported", url);
+                }
+                supported
+            })
+            .collect();
+        Self {
+            urls,
+            secret: std::env::var("WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty()),
+            max_retries: get_env_or("WEBHOOK_MAX_RETRIES", 5),
+            backoff_ms: get_env_or("WEBHOOK_BACKOFF_MS", 500),
+            timeout_ms: get_env_or("WEBHOOK_TIMEOUT_MS", 5000),
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `timestamp.body`, sent as `x-stickyapp-signature: sha256=<hex>`.
+/// Receivers recompute it with the shared secret and the `x-stickyapp-timestamp` header.
+pub fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Forward every lifecycle event to the configured urls, nothing is spawned without urls
+pub fn spawn_notifier(bus: &LifecycleBus, config: WebhookConfig) -> Option<JoinHandle<()>> {
+    if config.urls.is_empty() {
+        return None;
+    }
+    tracing::warn!("Webhooks: {:?}, signed: {}", config.urls, config.secret.is_some());
+    let mut events_rx = bus.subscribe();
+    let config = Arc::new(config);
+    let client: Client<HttpConnector> = Client::new();
+    Some(tokio::spawn(async move {
+        loop {
+            let event = match events_rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Webhooks fell behind, {} lifecycle events not delivered", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let body = match serde_json::to_vec(&event) {
+                Ok(body) => Bytes::from(body),
+                Err(e) => {
+                    tracing::warn!("Failed to serialize lifecycle event. {}", e);
+                    continue;
+                }
+            };
+            // each url retries on its own, so a slow receiver does not hold back the others
+            for url in &config.urls {
+                tokio::spawn(deliver(client.clone(), url.clone(), body.clone(), config.clone()));
+            }
+        }
+    }))
+}
+
+async fn deliver(client: Client<HttpConnector>, url: String, body: Bytes, config: Arc<WebhookConfig>) {
+    let mut backoff = Duration::from_millis(config.backoff_ms);
+    for attempt in 0..=config.max_retries {
+        let timestamp = Utc::now().timestamp().to_string();
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(url.as_str())
+            .header("content-type", "application/json")
+            .header("x-stickyapp-timestamp", timestamp.as_str());
+        if let Some(secret) = &config.secret {
+            builder = builder.header("x-stickyapp-signature", format!("sha256={}", sign(secret, &timestamp, &body)));
+        }
+        let request = match builder.body(Body::from(body.clone())) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::warn!("Invalid webhook request to {}. {}", url, e);
+                return;
+            }
+        };
+        let retry = match tokio::time::timeout(Duration::from_millis(config.timeout_ms), client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => {
+                tracing::debug!("Webhook delivered to {}", url);
+                return;
+            }
+            Ok(Ok(response)) => {
+                tracing::warn!("Webhook to {} answered {}, attempt {}", url, response.status(), attempt + 1);
+                // other client errors will not go away by retrying
+                response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("Webhook to {} failed, attempt {}. {}", url, attempt + 1, e);
+                true
+            }
+            Err(_) => {
+                tracing::warn!("Webhook to {} timed out, attempt {}", url, attempt + 1);
+                true
+            }
+        };
+        if !retry || attempt == config.max_retries {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    tracing::warn!("Giving up on webhook to {}", url);
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::marker::PhantomData;
+use axum::http::{header, HeaderMap};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+
+// A request's message, json text or, in any format, the message as a structured value
+enum WireMessage<M> {
+    Text(String),
+    Structured(M),
+}
+
+impl<'de, M: Deserialize<'de>> Deserialize<'de> for WireMessage<M> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MessageVisitor<M>(PhantomData<M>);
+
+        impl<'de, M: Deserialize<'de>> Visitor<'de> for MessageVisitor<M> {
+            type Value = WireMessage<M>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a json text or a structured message")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                Ok(WireMessage::Text(text.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                // decoded in the request's own format, so binary values such as byte strings survive
+                M::deserialize(de::value::MapAccessDeserializer::new(map)).map(WireMessage::Structured)
+            }
+        }
+
+        deserializer.deserialize_any(MessageVisitor(PhantomData))
+    }
+}
+
+#[derive(Deserialize)]
+struct WireRequest<M> {
+    message: WireMessage<M>,
+}
+
+// quality of a media range in an accept header, 1 unless given
+fn quality(media_range: &str) -> f32 {
+    media_range.split(';')
+        .skip(1)
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(1.)
+}
+
+/// Encodings accepted and produced by the session endpoints, json unless negotiated otherwise.
+/// Sessions always exchange json text messages, the binary formats carry them as structured
+/// values instead of json strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "application/json",
+            WireFormat::Cbor => "application/cbor",
+            WireFormat::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<Self> {
+        // ignore parameters such as charset or q
+        let essence = mime.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        match essence.as_str() {
+            "application/json" | "application/*" | "*/*" => Some(WireFormat::Json),
+            "application/cbor" => Some(WireFormat::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(WireFormat::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Format of a request body, json when no content type is given
+    pub fn from_content_type(headers: &HeaderMap) -> Result<Self, String> {
+        let content_type = match headers.get(header::CONTENT_TYPE) {
+            Some(value) => value.to_str().map_err(|_| String::from("Invalid content-type header"))?,
+            None => return Ok(WireFormat::Json),
+        };
+        WireFormat::from_mime(content_type).ok_or_else(|| format!(
+            "Unsupported content-type {}. Use application/json, application/cbor or application/msgpack", content_type
+        ))
+    }
+
+    /// Format of the response, the supported type with the highest quality in the accept header,
+    /// the first of them on ties, json otherwise
+    pub fn from_accept(headers: &HeaderMap) -> Self {
+        let mut best: Option<(f32, WireFormat)> = None;
+        let media_ranges = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for media_range in media_ranges {
+            let format = match WireFormat::from_mime(media_range) {
+                Some(format) => format,
+                None => continue,
+            };
+            // q=0 marks a type as not acceptable
+            let quality = quality(media_range);
+            let better = match best {
+                Some((best_quality, _)) => quality > best_quality,
+                None => true,
+            };
+            if quality > 0. && better {
+                best = Some((quality, format));
+            }
+        }
+        best.map_or(WireFormat::Json, |(_, format)| format)
+    }
+
+    /// Decode a request's message into the json text sessions expect. A structured message is
+    /// decoded as `M` straight from the request's format.
+    pub fn decode_message<M: for<'de> Deserialize<'de> + Serialize>(&self, body: &[u8]) -> Result<String, String> {
+        let request: WireRequest<M> = match self {
+            WireFormat::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            WireFormat::Cbor => serde_cbor::from_slice(body).map_err(|e| e.to_string()),
+            WireFormat::MessagePack => rmp_serde::from_read_ref(body).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Failed to decode {} request. {}", self.content_type(), e))?;
+        match request.message {
+            WireMessage::Text(message) => Ok(message),
+            WireMessage::Structured(message) => serde_json::to_string(&message).map_err(|e| format!("Invalid request. {}", e)),
+        }
+    }
+
+    /// Encode a response. In the binary formats a json `message` is embedded as a structured value.
+    pub fn encode_response<T: Serialize>(&self, response: &T) -> Result<Vec<u8>, String> {
+        let mut response = serde_json::to_value(response).map_err(|e| format!("Failed to encode response. {}", e))?;
+        if *self != WireFormat::Json {
+            if let Some(message) = response.get_mut("message") {
+                let structured = message
+                    .as_str()
+                    .and_then(|text| serde_json::from_str::<serde_json::Value>(text).ok())
+                    .filter(|value| value.is_object() || value.is_array());
+                if let Some(structured) = structured {
+                    *message = structured;
+                }
+            }
+        }
+        match self {
+            WireFormat::Json => serde_json::to_vec(&response).map_err(|e| e.to_string()),
+            WireFormat::Cbor => serde_cbor::to_vec(&response).map_err(|e| e.to_string()),
+            WireFormat::MessagePack => rmp_serde::to_vec_named(&response).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Failed to encode {} response. {}", self.content_type(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use std::collections::BTreeMap;
+    use crate::session_common::SessionRequestMessage;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn accept_prefers_the_highest_quality() {
+        assert_eq!(WireFormat::from_accept(&HeaderMap::new()), WireFormat::Json);
+        assert_eq!(WireFormat::from_accept(&accept("application/cbor")), WireFormat::Cbor);
+        assert_eq!(WireFormat::from_accept(&accept("application/json;q=0.5, application/msgpack")), WireFormat::MessagePack);
+        assert_eq!(WireFormat::from_accept(&accept("application/cbor;q=0.2, application/msgpack;q=0.8")), WireFormat::MessagePack);
+        // ties go to the first, unsupported types are skipped
+        assert_eq!(WireFormat::from_accept(&accept("text/html, application/cbor, application/msgpack")), WireFormat::Cbor);
+    }
+
+    #[test]
+    fn accept_skips_unacceptable_types() {
+        assert_eq!(WireFormat::from_accept(&accept("application/cbor;q=0, */*;q=0.1")), WireFormat::Json);
+        assert_eq!(WireFormat::from_accept(&accept("application/msgpack; q=0")), WireFormat::Json);
+    }
+
+    #[test]
+    fn cbor_messages_carry_byte_strings() {
+        let text = |value: &str| serde_cbor::Value::Text(value.to_string());
+        let mut message = BTreeMap::new();
+        message.insert(text("action"), text("upload_ciphertexts"));
+        message.insert(text("ciphertexts_binary"), serde_cbor::Value::Bytes(vec![1, 2, 3]));
+        let mut request = BTreeMap::new();
+        request.insert(text("message"), serde_cbor::Value::Map(message));
+        let body = serde_cbor::to_vec(&serde_cbor::Value::Map(request)).unwrap();
+
+        let message = WireFormat::Cbor.decode_message::<SessionRequestMessage>(&body).unwrap();
+        let decoded: SessionRequestMessage = serde_json::from_str(&message).unwrap();
+        assert_eq!(decoded.action, "upload_ciphertexts");
+        assert_eq!(decoded.ciphertexts_binary.as_deref(), Some("AQID"));
+    }
+
+    #[test]
+    fn text_messages_pass_through() {
+        let body = br#"{"message": "{\"action\": \"mean\"}"}"#;
+        assert_eq!(WireFormat::Json.decode_message::<SessionRequestMessage>(body).unwrap(), r#"{"action": "mean"}"#);
+    }
+}
@@ -6,11 +6,32 @@ curl http://localhost:8080/
 # list sessions
 curl -s http://localhost:8080/sessions | jq
 
+# remaining session capacity
+curl -s http://localhost:8080/capacity | jq
+
 # create normal session
 curl -v -s -H 'Content-Type: application/json' http://localhost:8080/sessions -d '{"message": "{}"}' | jq
 
 # create another normal session and store session id
 SID=$(curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions -d '{"message": "{}"}' | jq -r '.sessionid')
+# a retried create with the same idempotency key returns the same session, a different init message conflicts
+curl -s -H 'Content-Type: application/json' -H 'Idempotency-Key: create-1' http://localhost:8080/sessions -d '{"message": "{}"}' | jq
+curl -s -H 'Content-Type: application/json' -H 'Idempotency-Key: create-1' http://localhost:8080/sessions -d '{"message": "{}"}' | jq
+curl -s -H 'Content-Type: application/json' -H 'Idempotency-Key: create-1' http://localhost:8080/sessions -d '{"message": "{\"other\": 1}"}' | jq
+# client chosen session id
+curl -s -H 'Content-Type: application/json' 'http://localhost:8080/sessions?sessionid=my-session' -d '{"message": "{}"}' | jq
+# observe the session's events while the actions below run
+timeout 5 curl -s -N http://localhost:8080/sessions/$SID/events &
+
+# a retried encrypt with the same idempotency key is applied once
+curl -s -H 'Content-Type: application/json' -H 'Idempotency-Key: encrypt-1' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 1}"}' | jq
+curl -s -H 'Content-Type: application/json' -H 'Idempotency-Key: encrypt-1' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 1}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"count\", \"consume\": false}"}' | jq
+# sequenced actions apply in order, a gap or a repeat is rejected and last_seq tells where the session is
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 2, \"seq\": 1}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 4, \"seq\": 3}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 3, \"seq\": 2}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 3, \"seq\": 2}"}' | jq
 
 # list sessions
 curl -s http://localhost:8080/sessions | jq
@@ -19,21 +40,71 @@ curl -s http://localhost:8080/sessions | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 1}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 2}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 3}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"summary\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"percentile\", \"percentiles\": [25, 50, 90]}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"histogram\", \"bins\": 3}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"mean\", \"value\": 0}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 5, \"stream\": \"latency\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"create_stream\", \"stream\": \"rolling\", \"window_size\": 2, \"half_life_secs\": 60}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 1, \"stream\": \"rolling\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 2, \"stream\": \"rolling\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 3, \"stream\": \"rolling\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"rolling\", \"stream\": \"rolling\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt_vector\", \"vector\": [1, 2, 3], \"stream\": \"features\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt_vector\", \"vector\": [3, 4, 5], \"stream\": \"features\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"vector_mean\", \"stream\": \"features\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"list_streams\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"mean\", \"stream\": \"latency\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"checkpoint\", \"label\": \"before\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 100}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"rollback\", \"label\": \"before\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"list_checkpoints\"}"}' | jq
+# fork the session, the fork starts with a copy of the values and changes apart from its source
+FORK=$(curl -s -X POST http://localhost:8080/sessions/$SID/fork | jq -r '.sessionid')
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$FORK -d '{"message": "{\"action\": \"encrypt\", \"value\": 100}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$FORK -d '{"message": "{\"action\": \"summary\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"summary\"}"}' | jq
+curl -s -X POST 'http://localhost:8080/sessions/'$SID'/fork?sessionid=my-fork' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"shutdown\", \"value\": 0}"}' | jq
 curl -s http://localhost:8080/sessions | jq
 sleep 2
 
+# group session: each participant contributes with their own token, mean is released once a quorum contributed
+GROUP=$(curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions?encrypted=true \
+  -d '{"message": "{\"group\": {\"participants\": [\"alice\", \"bob\", \"carol\"], \"quorum\": 2, \"hide_contributions\": true}}"}')
+echo $GROUP | jq
+SID=$(echo $GROUP | jq -r '.sessionid')
+ALICE=$(echo $GROUP | jq -r '.participants[] | select(.participant == "alice") | .token')
+BOB=$(echo $GROUP | jq -r '.participants[] | select(.participant == "bob") | .token')
+# only participants observe a group session's events
+curl -s http://localhost:8080/sessions/$SID/events | jq
+timeout 5 curl -s -N "http://localhost:8080/sessions/$SID/events?participant_token=$ALICE" &
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 3, \"participant_token\": \"'$ALICE'\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"mean\", \"participant_token\": \"'$ALICE'\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 5, \"participant_token\": \"'$BOB'\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"summary\", \"participant_token\": \"'$BOB'\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 7, \"participant_token\": \"unknown\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"contributions\", \"participant_token\": \"'$BOB'\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"mean\", \"participant_token\": \"'$BOB'\"}"}' | jq
+curl -s -X POST http://localhost:8080/sessions/$SID/fork | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"shutdown\", \"participant_token\": \"'$ALICE'\"}"}' | jq
+
 # create an encrypted session with default parameters
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions?encrypted=true -d '{"message": "{}"}'
 
 # create an encrypted session with encryption parameters specified
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions?encrypted=true \
--d '{"message": "{\"encoder_min\": 1.0, \"encoder_max\": 99, \"encoder_precision_bits\": 10, \"encoder_padding_bits\": 4, \"secret_key_dimensions\": 1024, \"secret_key_log2_std_dev\": -40}"}'
+-d '{"message": "{\"encoder_min\": 1.0, \"encoder_max\": 99, \"encoder_precision_bits\": 10, \"encoder_padding_bits\": 11, \"secret_key_dimensions\": 1024, \"secret_key_log2_std_dev\": -40}"}'
+
+# create an encrypted session from a preset, overriding one parameter
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions?encrypted=true -d '{"message": "{\"preset\": \"secure128\", \"encoder_max\": 10}"}'
+
+# invalid encryption parameters are rejected with the parameter to change
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions?encrypted=true -d '{"message": "{\"secret_key_dimensions\": 512, \"secret_key_log2_std_dev\": -40}"}'
 
 # create encrypted session and store session id
 SID=$(curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions?encrypted=true \
--d '{"message": "{\"encoder_min\": 1.0, \"encoder_max\": 64, \"encoder_precision_bits\": 10, \"encoder_padding_bits\": 4, \"secret_key_dimensions\": 1024, \"secret_key_log2_std_dev\": -40}"}' | jq -r '.sessionid')
+-d '{"message": "{\"encoder_min\": 1.0, \"encoder_max\": 64, \"encoder_precision_bits\": 10, \"encoder_padding_bits\": 11, \"secret_key_dimensions\": 1024, \"secret_key_log2_std_dev\": -40, \"bootstrap\": true}"}' | jq -r '.sessionid')
 
 # list sessions
 curl -s http://localhost:8080/sessions | jq
@@ -49,6 +120,25 @@ curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 8}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 9}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"encrypt\", \"value\": 10}"}' | jq
+curl -s http://localhost:8080/sessions/$SID | jq
+curl -s http://localhost:8080/metrics
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"sum\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"sum\", \"tolerance\": 0.001}"}' | jq '.message | fromjson | .precision'
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"weighted_mean\", \"weights\": [1, 1, 1, 1, 1, 2, 2, 2, 2, 2]}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"variance\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"export_parameters\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"export_key\", \"token\": \"wrong\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"download_ciphertexts\", \"index\": 0}"}' | jq
+curl -s -H 'Content-Type: application/json' -H 'Accept: application/cbor' http://localhost:8080/sessions/$SID -d '{"message": {"action": "sum"}}' | xxd | head -5
+curl -s -H 'Content-Type: application/json' -H 'Accept: application/msgpack' http://localhost:8080/sessions/$SID -d '{"message": {"action": "sum"}}' | xxd | head -5
+curl -s -o /tmp/ciphertexts.bin http://localhost:8080/sessions/$SID/ciphertexts
+curl -s -H 'Content-Type: application/octet-stream' --data-binary @/tmp/ciphertexts.bin http://localhost:8080/sessions/$SID/ciphertexts | jq
+# an encrypted fork shares the key and gets its own copy of the ciphertexts
+FORK=$(curl -s -X POST http://localhost:8080/sessions/$SID/fork | jq -r '.sessionid')
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$FORK -d '{"message": "{\"action\": \"sum\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"checkpoint\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"rotate_key\"}"}' | jq
+curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"rollback\"}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"mean\", \"value\": 0}"}' | jq
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/$SID -d '{"message": "{\"action\": \"shutdown\", \"value\": 0}"}' | jq
 curl -s http://localhost:8080/sessions | jq
@@ -57,5 +147,8 @@ sleep 2
 
 # action on a non existent session
 curl -s -H 'Content-Type: application/json' http://localhost:8080/sessions/acbdefg -d '{"message": "{\"action\": \"encrypt\", \"value\": 1}"}' | jq
+# grpc interface
+grpcurl -plaintext -import-path proto -proto session.proto -d '{"message": "{}"}' localhost:50051 stickyapp.SessionService/CreateSession
+grpcurl -plaintext -import-path proto -proto session.proto localhost:50051 stickyapp.SessionService/ListSessions
 # shutdown the server
 curl http://localhost:8080/shutdown
@@ -0,0 +1,42 @@
+#!/usr/bin/env python3
+# Local stand-in for a webhook receiver. Prints every lifecycle event and checks its signature.
+# Run the server with WEBHOOK_URLS=http://localhost:8090/events WEBHOOK_SECRET=<secret>
+# and this receiver with WEBHOOK_SECRET=<secret>. FAIL_FIRST=n answers 500 to the first n
+# deliveries, to watch the server retry.
+import hashlib
+import hmac
+import json
+import os
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+SECRET = os.getenv('WEBHOOK_SECRET', '')
+FAIL_FIRST = int(os.getenv('FAIL_FIRST', '0'))
+PORT = int(os.getenv('PORT', '8090'))
+
+class WebhookHandler(BaseHTTPRequestHandler):
+    deliveries = 0
+
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get('Content-Length', 0)))
+        WebhookHandler.deliveries += 1
+        if WebhookHandler.deliveries <= FAIL_FIRST:
+            print("failing delivery", WebhookHandler.deliveries)
+            self.send_response(500)
+            self.end_headers()
+            return
+
+        signed = "unsigned"
+        if SECRET:
+            timestamp = self.headers.get('x-stickyapp-timestamp', '')
+            expected = hmac.new(SECRET.encode(), timestamp.encode() + b'.' + body, hashlib.sha256).hexdigest()
+            received = self.headers.get('x-stickyapp-signature', '')
+            signed = "valid" if hmac.compare_digest(received, 'sha256=' + expected) else "INVALID"
+        print(signed, json.dumps(json.loads(body)))
+        self.send_response(204)
+        self.end_headers()
+
+    def log_message(self, format, *args):
+        pass
+
+if __name__ == '__main__':
+    HTTPServer(('0.0.0.0', PORT), WebhookHandler).serve_forever()